@@ -1,20 +1,29 @@
 use clap::Parser;
-use log::{info, warn};
-use std::io::BufWriter;
+use log::{error, info};
+use rayon::prelude::*;
+use std::io::{self, BufWriter, SeekFrom};
 use std::io::prelude::*;
-use std::fs::File;
-use std::path::Path;
-use std::thread::sleep;
-use std::time::Duration;
-use regex::Regex;
-use tokio::runtime::Runtime;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cmp;
+use std::time::{Duration, Instant};
 
+use crate::commands::sidecar_path;
+use crate::error::{Error, Result};
+use crate::store::config::ConfigRoot;
 use crate::store::tile::Tile;
-use crate::models::record::{TileKeyframeHeader, write_record, TILE_KEYFRAME_VERSION_ID};
+use crate::models::{FrameData, FrameEncoding, UidFrameData, NO_UID};
+use crate::models::record::{
+  Placement, Record, TileKeyframeHeader, write_record, FRAME_FLAG_TABLE, FRAME_FLAG_TIMED, FRAME_FLAG_UIDS,
+  FRAME_FLAG_ZSTD, TILE_KEYFRAME_VERSION_ID
+};
 
-const REGEX_LOG: &str = r"^([A-Za-z0-9-]+)_log_([0-9]+_[0-9]+).bin$";
+const ZSTD_LEVEL: i32 = 3;
+// Renders timed by --report, spread evenly through the log
+const REPORT_SAMPLES: usize = 32;
 
-#[derive(Parser, Clone)]
+#[derive(Parser)]
 pub struct KeyframeCommand {
   #[clap(required=true)]
   interval: u32,
@@ -22,54 +31,515 @@ pub struct KeyframeCommand {
   // Tile size, tiles are square
   #[clap(required=true, min_values=1)]
   inputs: Vec<String>,
+
+  // How frames are stored, over the dataset's frame encoding. Defaults to
+  // raw without a dataset
+  #[clap(long, arg_enum)]
+  encoding: Option<FrameEncoding>,
+
+  // Dataset config, and the dataset in it whose frame encoding is used
+  #[clap(long, requires="dataset")]
+  config: Option<String>,
+  #[clap(long, requires="config")]
+  dataset: Option<String>,
+
+  // Also store who last placed each pixel, which speeds up uid-rem images
+  // when the indexes aren't loaded
+  #[clap(long)]
+  uids: bool,
+
+  // Take the interval as milliseconds of placement time rather than a number
+  // of placements, so replays from a timestamp have a bounded length in time
+  #[clap(long)]
+  by_time: bool,
+
+  // Also take a frame whenever this many placements have passed since the
+  // last, so no timestamp replays more than this many placements
+  #[clap(long)]
+  max_replay: Option<u32>,
+
+  // Most uncompressed frame data per tile in MiB, raising the interval and
+  // max replay as needed to fit
+  #[clap(long)]
+  frame_budget_mb: Option<u64>,
+
+  // Log the replay lengths of each tile and time some renders from it
+  #[clap(long)]
+  report: bool,
+
+  // Number of tiles to process at once, defaults to the number of CPUs
+  #[clap(long)]
+  threads: Option<usize>,
+}
+
+// How keyframes are taken and stored.
+#[derive(Clone, Copy)]
+pub struct KeyframeOptions {
+  pub interval: u32,
+  pub encoding: FrameEncoding,
+  pub uids: bool,
+  pub by_time: bool,
+  pub max_replay: Option<u32>,
+}
+
+impl KeyframeOptions {
+  fn flags(&self) -> u32 {
+    let mut flags = 0;
+    if self.encoding == FrameEncoding::Zstd {
+      flags |= FRAME_FLAG_ZSTD;
+    }
+    if self.uids {
+      flags |= FRAME_FLAG_UIDS;
+    }
+    if self.by_time || self.max_replay.is_some() {
+      flags |= FRAME_FLAG_TABLE;
+    }
+    if self.by_time {
+      flags |= FRAME_FLAG_TIMED;
+    }
+    flags
+  }
+
+  // Uncompressed bytes of each frame.
+  fn frame_size(&self, size: u16) -> u64 {
+    let pixels = size as u64 * size as u64;
+    if self.uids { pixels * 5 } else { pixels }
+  }
+
+  // Number of frames that fit in a budget of uncompressed frame data.
+  fn frames_in_budget(&self, budget_mb: u64, size: u16) -> usize {
+    (budget_mb.saturating_mul(1 << 20) / self.frame_size(size)) as usize
+  }
+
+  // Number of frames that would be taken for placements at the timestamps.
+  fn frame_count(&self, timestamps: &[u32]) -> usize {
+    let mut planner = FramePlanner::new(*self);
+    timestamps.iter().filter(|ts| planner.next(**ts).is_some()).count()
+  }
+
+  // Raises the interval, then the max replay, until there are at most
+  // max_frames frames. There is always at least one frame.
+  fn fit(mut self, timestamps: &[u32], max_frames: usize) -> KeyframeOptions {
+    let max_frames = cmp::max(max_frames, 1);
+    let max_replay = self.max_replay.take();
+    while self.frame_count(timestamps) > max_frames {
+      self.interval = if self.by_time {
+        self.interval.saturating_mul(2)
+      } else {
+        cmp::max(self.interval + 1, timestamps.len().div_ceil(max_frames) as u32)
+      };
+    }
+    if let Some(k) = max_replay {
+      // a max replay past the last placement never forces a frame so fits
+      let (mut lo, mut hi) = (k as usize, timestamps.len() + 1);
+      while lo < hi {
+        let mid = (lo + hi) / 2;
+        let options = KeyframeOptions { max_replay: Some(mid as u32), ..self };
+        if options.frame_count(timestamps) <= max_frames { hi = mid } else { lo = mid + 1 }
+      }
+      self.max_replay = Some(lo as u32);
+    }
+    self
+  }
+}
+
+// Decides where keyframes go as the placements are seen in order.
+struct FramePlanner {
+  options: KeyframeOptions,
+  seen: u32,
+  last_frame: u32,
+  next_ts: u64,
+}
+
+impl FramePlanner {
+  fn new(options: KeyframeOptions) -> FramePlanner {
+    FramePlanner { options, seen: 0, last_frame: 0, next_ts: 0 }
+  }
+
+  // Whether a frame is taken before the next placement, at ts, returning the
+  // timestamp to list it under.
+  fn next(&mut self, ts: u32) -> Option<u32> {
+    let KeyframeOptions { interval, by_time, max_replay, .. } = self.options;
+    let seen = self.seen;
+    self.seen += 1;
+    let frame_ts = if by_time {
+      (ts as u64 >= self.next_ts).then(|| {
+        let boundary = ts - ts % interval;
+        self.next_ts = boundary as u64 + interval as u64;
+        boundary
+      })
+    } else {
+      seen.is_multiple_of(interval).then_some(ts)
+    };
+    let frame_ts = frame_ts.or_else(|| match max_replay {
+      Some(k) if seen - self.last_frame >= k => Some(ts),
+      _ => None,
+    });
+    if frame_ts.is_some() {
+      self.last_frame = seen;
+    }
+    frame_ts
+  }
+}
+
+// The placements replayed from each frame, given the first placement after
+// each of count placements.
+fn replay_runs(starts: &[u32], count: u32) -> Vec<(u32, u32)> {
+  let ends = starts.iter().skip(1).copied().chain(std::iter::once(count));
+  let runs: Vec<_> = starts.iter().copied().zip(ends).collect();
+  if runs.is_empty() { vec![(0, count)] } else { runs }
 }
 
 impl KeyframeCommand {
-  pub fn execute(&self) {
-    let rt = Runtime::new().unwrap();
-    for input in self.inputs.iter() {
-      rt.spawn(export(self.clone(), String::from(input)));
+  pub fn execute(&self) -> Result<()> {
+    let pool = rayon::ThreadPoolBuilder::new()
+      .num_threads(self.threads.unwrap_or(0))
+      .build()
+      .map_err(|e| Error::InvalidArgument(e.to_string()))?;
+
+    let configured = match (&self.config, &self.dataset) {
+      (Some(config), Some(name)) => Some(ConfigRoot::load(config)?.dataset(name)?.frame_encoding),
+      _ => None,
+    };
+    let encoding = self.encoding.or(configured).unwrap_or_default();
+
+    let total = self.inputs.len();
+    let done = AtomicUsize::new(0);
+    let failed = pool.install(|| self.inputs.par_iter().with_max_len(1).filter(|input| {
+      let now = Instant::now();
+      let result = self.export(input, encoding);
+      let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+      match result {
+        Ok(()) => {
+          info!("[{}/{}] wrote keyframes for {} in {:?}", n, total, input, now.elapsed());
+          false
+        },
+        Err(e) => {
+          error!("[{}/{}] {}: {}", n, total, input, e);
+          true
+        }
+      }
+    }).count());
+
+    if failed > 0 {
+      return Err(Error::Failed(format!("{} of {} inputs failed", failed, total)));
+    }
+    Ok(())
+  }
+
+  fn export(&self, input: &str, encoding: FrameEncoding) -> Result<()> {
+    let out_path = match sidecar_path(input, "frame") {
+      Some(p) => p,
+      None => return Err(Error::InvalidArgument(format!("unable to match filename for {}", input))),
+    };
+
+    let tile = Tile::load(input)?;
+
+    let mut options = KeyframeOptions {
+      interval: self.interval,
+      encoding,
+      uids: self.uids,
+      by_time: self.by_time,
+      max_replay: self.max_replay,
+    };
+    if let Some(budget) = self.frame_budget_mb {
+      let max_frames = options.frames_in_budget(budget, tile.size);
+      let timestamps: Vec<u32> = tile.placements().iter().map(|p| Ok(p?.ts)).collect::<Result<_>>()?;
+      let fitted = options.fit(&timestamps, max_frames);
+      if fitted.interval != options.interval || fitted.max_replay != options.max_replay {
+        info!(
+          "{} needs an interval of {} and max replay of {:?} to fit in {} frames",
+          input, fitted.interval, fitted.max_replay, max_frames
+        );
+      }
+      options = fitted;
+    }
+
+    let fw = File::create(&out_path).map_err(Error::file(&out_path))?;
+    let mut w = BufWriter::new(fw);
+    let (header, starts) = write_keyframes(&tile, &mut w, &spill_path(&out_path), options)?;
+    info!("Wrote out {:?} with header {:?}", out_path, header);
+    if self.report {
+      report(input, &out_path, &starts, header.count)?;
+    }
+    Ok(())
+  }
+}
+
+// Logs how many placements queries against the keyframes replay, and how
+// long a sample of renders, including the longest replay, take.
+fn report(input: &str, frame_path: &Path, starts: &[u32], frames: u32) -> Result<()> {
+  let tile = Tile::load_with_frames(input, &frame_path.display().to_string())?;
+  let placements = tile.placements();
+  if placements.is_empty() {
+    return Ok(())
+  }
+
+  // a query at a placement replays it and the ones before it since the frame
+  let runs = replay_runs(starts, tile.count);
+  let longest = runs.iter().max_by_key(|(start, end)| end - start).copied().unwrap_or_default();
+  let total: u64 = runs.iter().map(|(start, end)| (end - start) as u64 * (end - start + 1) as u64 / 2).sum();
+  let mean = total as f64 / tile.count as f64;
+
+  let mut samples: Vec<usize> = (0..REPORT_SAMPLES).map(|i| i * placements.len() / REPORT_SAMPLES).collect();
+  samples.push(longest.1 as usize - 1);
+
+  let mut times = Vec::with_capacity(samples.len());
+  for i in samples.iter() {
+    let now = Instant::now();
+    tile.get_image_at_timestamp(tile.start + placements.get(*i)?.ts as u64);
+    times.push(now.elapsed());
+  }
+  let slowest = times.iter().max().copied().unwrap_or_default();
+  let average = times.iter().sum::<Duration>() / cmp::max(times.len(), 1) as u32;
+  info!(
+    "{}: {} frames, replays at most {} placements and {:.1} on average, renders took at most {:?} and {:?} on average over {} samples",
+    input, frames, longest.1 - longest.0, mean, slowest, average, times.len()
+  );
+  Ok(())
+}
+
+// Writes keyframes of the tile, as colour planes followed by uid planes if
+// asked for. Returns the header along with the first placement after each
+// frame.
+pub fn write_keyframes<W: Write + Seek>(
+  tile: &Tile,
+  w: &mut BufWriter<W>,
+  spill_path: &Path,
+  options: KeyframeOptions
+) -> Result<(TileKeyframeHeader, Vec<u32>)> {
+  let mut writer = KeyframeWriter::new(tile.size, tile.start_x, tile.start_y, options, spill_path)?;
+  w.write_all(&[0u8; TileKeyframeHeader::SIZE])?;
+  for p in tile.placements().iter() {
+    writer.push(&p?, w)?;
+  }
+  let starts = writer.starts.clone();
+  Ok((writer.finish(w)?, starts))
+}
+
+// Builds keyframes from placements pushed in order, taking frames where the
+// planner decides. Raw uid planes come after all the colour planes so are
+// kept in a spill file until the end.
+pub struct KeyframeWriter {
+  header: TileKeyframeHeader,
+  encoding: FrameEncoding,
+  planner: FramePlanner,
+  pushed: u32,
+  // the timestamp of each frame and the first placement after it
+  times: Vec<u32>,
+  starts: Vec<u32>,
+  colours: FrameData,
+  uids: Option<UidFrameData>,
+  // zstd compressed colour and uid planes
+  compressed: (Vec<Vec<u8>>, Vec<Vec<u8>>),
+  spill: Option<(PathBuf, BufWriter<File>)>,
+}
+
+impl KeyframeWriter {
+  pub fn new(
+    size: u16,
+    start_x: u16,
+    start_y: u16,
+    options: KeyframeOptions,
+    spill_path: &Path
+  ) -> Result<KeyframeWriter> {
+    let KeyframeOptions { interval, encoding, uids, max_replay, .. } = options;
+    if interval == 0 || max_replay == Some(0) {
+      return Err(Error::InvalidArgument(String::from("the keyframe interval and max replay must be at least 1")));
+    }
+    let spill = if uids && encoding == FrameEncoding::Raw {
+      let fw = File::create(spill_path).map_err(Error::file(spill_path))?;
+      Some((PathBuf::from(spill_path), BufWriter::new(fw)))
+    } else {
+      None
+    };
+    let pixels = size as usize * size as usize;
+    Ok(KeyframeWriter {
+      header: TileKeyframeHeader {
+        version: TILE_KEYFRAME_VERSION_ID,
+        flags: options.flags(),
+        size,
+        start_x,
+        start_y,
+        interval,
+        count: 0,
+      },
+      encoding,
+      planner: FramePlanner::new(options),
+      pushed: 0,
+      times: Vec::new(),
+      starts: Vec::new(),
+      colours: vec![1; pixels],
+      uids: if uids { Some(vec![NO_UID; pixels]) } else { None },
+      compressed: (Vec::new(), Vec::new()),
+      spill,
+    })
+  }
+
+  // w is the keyframe file, with room left for the header at the start.
+  pub fn push<W: Write>(&mut self, p: &Placement, w: &mut W) -> Result<()> {
+    if let Some(ts) = self.planner.next(p.ts) {
+      self.times.push(ts);
+      self.starts.push(self.pushed);
+      self.snapshot(w)?;
+    }
+    let pixel = p.x as usize + p.y as usize * self.header.size as usize;
+    self.colours[pixel] = p.color + 1;
+    if let Some(uids) = self.uids.as_mut() {
+      uids[pixel] = p.uid;
+    }
+    self.pushed += 1;
+    Ok(())
+  }
+
+  fn snapshot<W: Write>(&mut self, w: &mut W) -> Result<()> {
+    self.header.count += 1;
+    let uids = self.uids.as_ref().map(|u| u.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+    match self.encoding {
+      FrameEncoding::Raw => {
+        w.write_all(&self.colours)?;
+        if let (Some(uids), Some((path, spill))) = (uids, self.spill.as_mut()) {
+          spill.write_all(&uids).map_err(Error::file(path))?;
+        }
+      },
+      FrameEncoding::Zstd => {
+        self.compressed.0.push(zstd::bulk::compress(&self.colours, ZSTD_LEVEL)?);
+        if let Some(uids) = uids {
+          self.compressed.1.push(zstd::bulk::compress(&uids, ZSTD_LEVEL)?);
+        }
+      },
+    }
+    Ok(())
+  }
+
+  // Writes the rest of the frames and the header.
+  pub fn finish<W: Write + Seek>(mut self, w: &mut BufWriter<W>) -> Result<TileKeyframeHeader> {
+    match self.encoding {
+      FrameEncoding::Raw => {
+        if let Some((path, spill)) = self.spill.as_mut() {
+          spill.flush().map_err(Error::file(&path))?;
+          let mut r = File::open(&path).map_err(Error::file(&path))?;
+          io::copy(&mut r, w)?;
+        }
+      },
+      FrameEncoding::Zstd => {
+        let frames = self.compressed.0.iter().chain(self.compressed.1.iter());
+        let mut offset = 0u64;
+        w.write_all(&offset.to_le_bytes())?;
+        for frame in frames.clone() {
+          offset += frame.len() as u64;
+          w.write_all(&offset.to_le_bytes())?;
+        }
+        for frame in frames {
+          w.write_all(frame)?;
+        }
+      },
+    }
+    if self.header.flags & FRAME_FLAG_TABLE != 0 {
+      for (ts, start) in self.times.iter().zip(self.starts.iter()) {
+        w.write_all(&ts.to_le_bytes())?;
+        w.write_all(&start.to_le_bytes())?;
+      }
+    }
+    w.seek(SeekFrom::Start(0))?;
+    write_record(&self.header, w)?;
+    w.flush()?;
+    Ok(self.header.clone())
+  }
+}
+
+impl Drop for KeyframeWriter {
+  fn drop(&mut self) {
+    if let Some((path, _)) = self.spill.take() {
+      let _ = fs::remove_file(path);
     }
-    sleep(Duration::from_secs(1)); // remove this hack
   }
 }
 
-async fn export(cmd: KeyframeCommand, input: String) {
-  let re = Regex::new(REGEX_LOG).unwrap();
-  let path = Path::new(&input);
-  let filename = path.file_name().unwrap().to_str().unwrap();
-  let capture = match re.captures(filename) {
-    Some(c) => c,
-    None => {
-      warn!("unable to match filename for {}", filename);
-      return
-    }
-  };
-
-  let tile = Tile::load(&input).unwrap();
-
-  let name = capture.get(1).map_or("", |m| m.as_str());
-  let position = capture.get(2).map_or("", |m| m.as_str());
-  let out_path = path.parent().unwrap().join(format!("{}_frame_{}.bin", name, position));
-  let fw = File::create(&out_path).unwrap();
-  let mut w = BufWriter::new(fw);
-
-  let header = TileKeyframeHeader {
-    version: TILE_KEYFRAME_VERSION_ID,
-    size: tile.size,
-    start_x: tile.start_x,
-    start_y: tile.start_y,
-    interval: cmd.interval,
-    count: if tile.count % cmd.interval == 0 { tile.count/cmd.interval }
-      else { (tile.count/cmd.interval) + 1 }
-  };
-  info!("Writing out {:?} with header {:?}", out_path, header);
-  write_record(&header, &mut w).unwrap();
-  
-  let mut output: Vec<u32> = vec![1; tile.size as usize * tile.size as usize];
-  w.write(&(output.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>())).unwrap();
-  for i in tile.placements().chunks(cmd.interval as usize) {
-    tile.apply(&mut output, i);
-    w.write(&(output.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>())).unwrap();
-  }
-}
\ No newline at end of file
+// Where uid planes of the keyframe file are kept while it's written.
+pub fn spill_path(path: &Path) -> PathBuf {
+  let mut spill = PathBuf::from(path);
+  spill.set_extension("bin.uids");
+  spill
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn options(interval: u32, by_time: bool, max_replay: Option<u32>) -> KeyframeOptions {
+    KeyframeOptions { interval, encoding: FrameEncoding::Raw, uids: false, by_time, max_replay }
+  }
+
+  // The index and listed timestamp of each placement a frame is taken before.
+  fn frames(options: KeyframeOptions, timestamps: &[u32]) -> Vec<(usize, u32)> {
+    let mut planner = FramePlanner::new(options);
+    timestamps.iter().enumerate().filter_map(|(i, ts)| Some((i, planner.next(*ts)?))).collect()
+  }
+
+  #[test]
+  fn plans_by_count() {
+    let timestamps: Vec<u32> = (0..8).map(|i| i * 10).collect();
+    assert_eq!(frames(options(3, false, None), &timestamps), [(0, 0), (3, 30), (6, 60)]);
+    assert_eq!(frames(options(1, false, None), &timestamps).len(), 8);
+  }
+
+  #[test]
+  fn plans_by_time() {
+    // frames are listed at the start of the interval they're taken in, and
+    // intervals without placements are skipped
+    let timestamps = [5, 50, 120, 130, 450, 460, 999];
+    assert_eq!(frames(options(100, true, None), &timestamps), [(0, 0), (2, 100), (4, 400), (6, 900)]);
+    assert_eq!(frames(options(1000, true, None), &timestamps), [(0, 0)]);
+  }
+
+  #[test]
+  fn plans_max_replay() {
+    let timestamps: Vec<u32> = (0..11).collect();
+    let planned = frames(options(10, false, Some(3)), &timestamps);
+    assert_eq!(planned.iter().map(|f| f.0).collect::<Vec<_>>(), [0, 3, 6, 9, 10]);
+
+    // placements sharing an interval of time still get frames
+    let planned = frames(options(1000, true, Some(3)), &[7; 8]);
+    assert_eq!(planned, [(0, 0), (3, 7), (6, 7)]);
+  }
+
+  #[test]
+  fn fits_frame_count() {
+    let timestamps: Vec<u32> = (0..100).map(|i| i * 10).collect();
+
+    let fitted = options(10, false, None).fit(&timestamps, 4);
+    assert_eq!(fitted.interval, 25);
+    assert_eq!(fitted.frame_count(&timestamps), 4);
+    let fitted = options(10, false, None).fit(&timestamps, 0);
+    assert_eq!(fitted.frame_count(&timestamps), 1);
+    // already fits
+    assert_eq!(options(10, false, None).fit(&timestamps, 10).interval, 10);
+
+    let fitted = options(100, true, None).fit(&timestamps, 3);
+    assert_eq!(fitted.interval, 400);
+    assert_eq!(fitted.frame_count(&timestamps), 3);
+
+    // the interval fits without the max replay, which is raised to the
+    // smallest that fits
+    let fitted = options(10, false, Some(2)).fit(&timestamps, 20);
+    assert_eq!((fitted.interval, fitted.max_replay), (10, Some(5)));
+    assert_eq!(fitted.frame_count(&timestamps), 20);
+  }
+
+  #[test]
+  fn fits_frame_budget() {
+    let raw = options(1, false, None);
+    assert_eq!(raw.frames_in_budget(10, 1024), 10);
+    assert_eq!(raw.frames_in_budget(0, 1024), 0);
+    // saturates rather than overflowing
+    assert_eq!(raw.frames_in_budget(u64::MAX, 1), u64::MAX as usize);
+    let uids = KeyframeOptions { uids: true, ..raw };
+    assert_eq!(uids.frames_in_budget(10, 1024), 2);
+
+    let timestamps: Vec<u32> = (0..1000).collect();
+    let fitted = uids.fit(&timestamps, uids.frames_in_budget(10, 1024));
+    assert_eq!(fitted.interval, 500);
+    assert_eq!(fitted.frame_count(&timestamps), 2);
+  }
+}
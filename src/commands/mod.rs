@@ -1,20 +1,81 @@
-use clap::Parser;
+use clap::{Args, Parser};
+use log::warn;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+use crate::error::Result;
+use crate::store::tile::Tile;
 
 pub mod keyframe;
 pub mod parse;
+pub mod pixel_index;
 pub mod serve;
+pub mod timelapse;
+pub mod upgrade;
+pub mod user_index;
+pub mod verify;
 
 #[derive(Parser)]
 pub enum SubCommand {
   Keyframe(keyframe::KeyframeCommand),
   Parse(parse::ParseCommand),
+  PixelIndex(pixel_index::PixelIndexCommand),
   Serve(serve::ServeCommand),
+  Timelapse(timelapse::TimelapseCommand),
+  Upgrade(upgrade::UpgradeCommand),
+  UserIndex(user_index::UserIndexCommand),
+  Verify(verify::VerifyCommand),
 }
 
-pub fn run_command(sub: SubCommand) {
+pub fn run_command(sub: SubCommand) -> Result<()> {
   match sub {
     SubCommand::Keyframe(cmd) => cmd.execute(),
     SubCommand::Parse(cmd) => cmd.execute(),
-    SubCommand::Serve(cmd) => cmd.execute()
+    SubCommand::PixelIndex(cmd) => cmd.execute(),
+    SubCommand::Serve(cmd) => cmd.execute(),
+    SubCommand::Timelapse(cmd) => cmd.execute(),
+    SubCommand::Upgrade(cmd) => cmd.execute(),
+    SubCommand::UserIndex(cmd) => cmd.execute(),
+    SubCommand::Verify(cmd) => cmd.execute(),
   }
-}
\ No newline at end of file
+}
+
+const REGEX_LOG: &str = r"^([A-Za-z0-9-]+)_log_([0-9]+_[0-9]+).bin$";
+
+// Path of a file of the given kind stored alongside a tile's placement log,
+// eg. data/2017_frame_0_0.bin for data/2017_log_0_0.bin.
+pub fn sidecar_path(input: &str, kind: &str) -> Option<PathBuf> {
+  let re = Regex::new(REGEX_LOG).unwrap();
+  let path = Path::new(input);
+  let capture = re.captures(path.file_name()?.to_str()?)?;
+
+  let name = capture.get(1).map_or("", |m| m.as_str());
+  let position = capture.get(2).map_or("", |m| m.as_str());
+  Some(path.parent()?.join(format!("{}_{}_{}.bin", name, kind, position)))
+}
+
+// Arguments shared by the commands that index tile logs.
+#[derive(Args)]
+pub struct IndexArgs {
+  // Tile placement logs to index
+  #[clap(required=true, min_values=1)]
+  inputs: Vec<String>,
+}
+
+impl IndexArgs {
+  // Loads each input and writes its index with export to the sidecar file of
+  // the given kind, skipping inputs that aren't named like a tile log.
+  pub fn each<F: Fn(&Tile, &Path) -> Result<()>>(&self, kind: &str, export: F) -> Result<()> {
+    for input in self.inputs.iter() {
+      let out_path = match sidecar_path(input, kind) {
+        Some(p) => p,
+        None => {
+          warn!("unable to match filename for {}", input);
+          continue
+        }
+      };
+      export(&Tile::load(input)?, &out_path)?;
+    }
+    Ok(())
+  }
+}
@@ -1,16 +1,31 @@
 use clap::Parser;
-use log::{error, info, warn};
-use serde::Deserialize;
-use std::mem;
-use std::cmp;
-use std::io::{BufWriter, Write, SeekFrom, prelude::*};
+use csv::ByteRecord;
+use flate2::bufread::MultiGzDecoder;
+use log::{info, warn};
+use rayon::prelude::*;
+use std::{cmp, io, mem, thread};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write, SeekFrom, prelude::*};
 use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, SyncSender};
 
-use crate::models::record::{TILE_PLACEMENT_VERSION_ID, TilePlacementHeader, Placement, write_record};
+use crate::error::{Error, Result};
+use crate::commands::keyframe::{spill_path, KeyframeOptions, KeyframeWriter};
+use crate::schema::{CanvasBounds, InputSchema, InputShape, InputUser, RecordReader};
+use crate::store::config::ConfigRoot;
+use crate::models::{FrameEncoding, NO_UID};
+use crate::models::record::{
+  TILE_PLACEMENT_VERSION_ID, LOG_FLAG_COMPACT, Record, TileKeyframeHeader, TilePlacementHeader, Placement, write_record
+};
+use crate::store::placements::CompactWriter;
+use crate::store::users::write_user_dictionary;
 
 #[derive(Parser)]
 pub struct ParseCommand {
-    // Input CSV
+    // Input CSV, - for stdin, or a glob of CSV parts read in sorted order.
+    // Gzipped input is decompressed as it's read
     #[clap(required=true)]
     input: String,
 
@@ -29,148 +44,635 @@ pub struct ParseCommand {
     // Tile size, tiles are square
     #[clap(required=true)]
     size_tile: u16,
+
+    // Write compressed blocks of bit-packed placements, which are much smaller
+    // but slower to read
+    #[clap(long)]
+    compact: bool,
+
+    // Also write keyframes every N placements, as the keyframe command would
+    #[clap(long)]
+    keyframe_interval: Option<u32>,
+
+    // How keyframes written during parsing are stored, over the dataset's
+    // frame encoding. Defaults to raw without a dataset
+    #[clap(long, arg_enum)]
+    keyframe_encoding: Option<FrameEncoding>,
+
+    // Also store who last placed each pixel in the keyframes
+    #[clap(long)]
+    keyframe_uids: bool,
+
+    // Take the keyframe interval as milliseconds rather than placements
+    #[clap(long)]
+    keyframe_by_time: bool,
+
+    // More inputs or globs to read after the first, in order
+    #[clap(long = "input")]
+    more_inputs: Vec<String>,
+
+    // Built in layout of the input, used over the dataset's schema. Defaults
+    // to plain when the dataset has none
+    #[clap(long, arg_enum)]
+    schema: Option<InputSchema>,
+
+    // Dataset config, and the dataset in it whose schema the input is read
+    // with and whose palette hex colours are matched against
+    #[clap(long, requires="dataset")]
+    config: Option<String>,
+    #[clap(long, requires="config")]
+    dataset: Option<String>,
+
+    // Input coordinates of the top left pixel of the canvas, for inputs
+    // centred on 0,0. Defaults to the dataset's origin, or 0,0
+    #[clap(long, allow_hyphen_values=true)]
+    origin_x: Option<i32>,
+    #[clap(long, allow_hyphen_values=true)]
+    origin_y: Option<i32>,
 }
 
 impl ParseCommand {
-  pub fn execute(&self) {
-    read_csv(&self.input, &self.output_prefix, self.size_x, self.size_y, self.size_tile).unwrap();
-  }
-}
-
-#[derive(Debug, Deserialize)]
-struct CSVRecord {
-  ts: u64,
-  user_id: u32,
-  x_coordinate: u16,
-  y_coordinate: u16,
-  x2_coordinate: Option<u16>,
-  y2_coordinate: Option<u16>,
-  color: u8
-}
-
-
-fn read_csv(
-  input: &String,
-  output_prefix: &String,
-  size_x: u16,
-  size_y: u16,
-  size_tile: u16
-) -> Result<(), ()> {
-  info!("{}", mem::size_of::<TilePlacementHeader>());
-  if size_x == 0 || size_y == 0 || size_x % size_tile != 0 || size_y % size_tile != 0 {
-    error!("The size of the canvas must be divisible by the tile size");
-    return Err(())
-  }
-  let tiles_x = size_x / size_tile;
-  let tiles_y = size_y / size_tile;
-  let n_tiles = tiles_x as usize * tiles_y as usize;
-  let mut headers: Vec<TilePlacementHeader> = Vec::with_capacity(n_tiles);
-  let mut handles: Vec<BufWriter<File>> = Vec::with_capacity(n_tiles);
-
-  for ty in 0..tiles_y {
-    for tx in 0..tiles_x {
-      headers.push(TilePlacementHeader{
-        size: size_tile,
-        start_x: tx * size_tile,
-        start_y: ty * size_tile,
-        start: 0,
-        count: 0,
-        uid_count: 0,
-        version: TILE_PLACEMENT_VERSION_ID,
-      });
+  pub fn execute(&self) -> Result<()> {
+    read_csv(self)
+  }
+}
+
+// Bytes of CSV handed to each decoding thread, split at a line end
+const CHUNK_SIZE: usize = 4 * (1 << 20); // 4MB
+// First bytes of every gzip member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// How the canvas is split into tiles.
+#[derive(Clone, Copy)]
+struct TileGrid {
+  size_tile: u16,
+  tiles_x: u16,
+  tiles_y: u16,
+  // input coordinates of the top left pixel
+  origin_x: i32,
+  origin_y: i32,
+}
+
+impl TileGrid {
+  // Index of the tile holding the input position x, y and the position
+  // within it.
+  fn locate(&self, x: i64, y: i64) -> Option<(usize, u16, u16)> {
+    let (cx, cy) = (x - self.origin_x as i64, y - self.origin_y as i64);
+    let size_tile = self.size_tile as i64;
+    if cx < 0 || cy < 0 || cx / size_tile >= self.tiles_x as i64 || cy / size_tile >= self.tiles_y as i64 {
+      warn!("position {},{} does not belong to a tile", x, y);
+      return None;
+    }
+    let (tile_x, tile_y) = (cx / size_tile, cy / size_tile);
+    Some((
+      tile_y as usize * self.tiles_x as usize + tile_x as usize,
+      (cx - tile_x * size_tile) as u16,
+      (cy - tile_y * size_tile) as u16,
+    ))
+  }
+
+  fn bounds(&self) -> CanvasBounds {
+    let (x0, y0) = (self.origin_x as i64, self.origin_y as i64);
+    CanvasBounds {
+      x0,
+      y0,
+      x1: x0 + self.tiles_x as i64 * self.size_tile as i64,
+      y1: y0 + self.tiles_y as i64 * self.size_tile as i64,
+    }
+  }
+}
+
+// The placements in a chunk of the CSV grouped by tile, in file order, with
+// the timestamp before it's made relative to the first record. With hashed
+// user ids placements hold an index into users until they're interned.
+struct ParsedChunk {
+  first_ts: Option<u64>,
+  records: usize,
+  tiles: Vec<Vec<(u64, Placement)>>,
+  users: Vec<Vec<u8>>,
+}
+
+// A tile's log, and optionally keyframes, while they're being written.
+struct TileOutput {
+  index: usize,
+  header: TilePlacementHeader,
+  handle: BufWriter<File>,
+  compact_writer: Option<CompactWriter>,
+  keyframes: Option<(KeyframeWriter, BufWriter<File>)>,
+}
+
+// Decoded chunks in file order, and the timestamp of the first record.
+type Batch = (u64, Arc<Vec<ParsedChunk>>);
+
+// A chunk of CSV with the input and line it starts at.
+type RawChunk = (usize, u64, Vec<u8>);
+
+// Chunks are read on one thread, decoded in parallel batches, then each
+// batch is shared with writer threads that each own some of the tiles. Every
+// tile sees its placements in file order, so the output is the same as
+// reading the CSV sequentially.
+fn read_csv(cmd: &ParseCommand) -> Result<()> {
+  let (output_prefix, compact) = (&cmd.output_prefix, cmd.compact);
+  let (size_x, size_y, size_tile) = (cmd.size_x, cmd.size_y, cmd.size_tile);
+  if size_x == 0 || size_y == 0 || !size_x.is_multiple_of(size_tile) || !size_y.is_multiple_of(size_tile) {
+    return Err(Error::InvalidArgument(String::from("the size of the canvas must be divisible by the tile size")))
+  }
+  let inputs = expand_inputs(std::iter::once(&cmd.input).chain(cmd.more_inputs.iter()))?;
+  let (reader, headers) = open_input(&inputs[0])?;
+  let config = cmd.config.as_deref().map(ConfigRoot::load).transpose()?;
+  let dataset = match (&config, &cmd.dataset) {
+    (Some(config), Some(name)) => Some(config.dataset(name)?),
+    _ => None,
+  };
+  let schema = match (cmd.schema, dataset.and_then(|d| d.schema.as_ref())) {
+    (Some(schema), _) => schema.schema(&headers),
+    (None, Some(schema)) => schema.clone(),
+    (None, None) => InputSchema::Plain.schema(&headers),
+  };
+  let palette = dataset.map(|d| &d.palette[..]);
+  let record_reader = RecordReader::new(&schema, &headers, palette, cmp::max(size_x, size_y))?;
+  let grid = TileGrid {
+    size_tile,
+    tiles_x: size_x / size_tile,
+    tiles_y: size_y / size_tile,
+    origin_x: cmd.origin_x.or(dataset.map(|d| d.origin_x)).unwrap_or(0),
+    origin_y: cmd.origin_y.or(dataset.map(|d| d.origin_y)).unwrap_or(0),
+  };
+  let n_tiles = grid.tiles_x as usize * grid.tiles_y as usize;
+  let n_writers = cmp::min(n_tiles, rayon::current_num_threads());
+  let mut outputs: Vec<Vec<TileOutput>> = (0..n_writers).map(|_| Vec::new()).collect();
+
+  for ty in 0..grid.tiles_y {
+    for tx in 0..grid.tiles_x {
       let filename = format!("{}_log_{}_{}.bin", output_prefix, tx, ty);
-      let fw = File::create(filename).unwrap();
+      let fw = File::create(&filename).map_err(Error::file(&filename))?;
       let mut handle = BufWriter::new(fw);
-      handle.write(&[0u8; mem::size_of::<TilePlacementHeader>()]).unwrap();
-      handles.push(handle);
+      handle.write_all(&[0u8; TilePlacementHeader::SIZE])?;
+      let index = ty as usize * grid.tiles_x as usize + tx as usize;
+      let keyframes = match cmd.keyframe_interval {
+        Some(interval) => {
+          let filename = format!("{}_frame_{}_{}.bin", output_prefix, tx, ty);
+          let options = KeyframeOptions {
+            interval,
+            encoding: cmd.keyframe_encoding.or(dataset.map(|d| d.frame_encoding)).unwrap_or_default(),
+            uids: cmd.keyframe_uids,
+            by_time: cmd.keyframe_by_time,
+            max_replay: None,
+          };
+          let writer = KeyframeWriter::new(
+            size_tile, tx * size_tile, ty * size_tile, options, &spill_path(Path::new(&filename))
+          )?;
+          let fw = File::create(&filename).map_err(Error::file(&filename))?;
+          let mut handle = BufWriter::new(fw);
+          handle.write_all(&[0u8; TileKeyframeHeader::SIZE])?;
+          Some((writer, handle))
+        },
+        None => None,
+      };
+      outputs[index % n_writers].push(TileOutput {
+        index,
+        header: TilePlacementHeader{
+          size: size_tile,
+          start_x: tx * size_tile,
+          start_y: ty * size_tile,
+          start: 0,
+          count: 0,
+          max_uid: 0,
+          version: TILE_PLACEMENT_VERSION_ID,
+          flags: if compact { LOG_FLAG_COMPACT } else { 0 },
+        },
+        handle,
+        compact_writer: if compact { Some(CompactWriter::new(size_tile)) } else { None },
+        keyframes,
+      });
     }
   }
 
-  let mut reader = csv::ReaderBuilder::new()
-    .delimiter(b',')
-    .buffer_capacity(4 * (1 << 20)) // 4MB
-    .from_path(input)
-    .expect("oops");
-
-  let mut first = false;
-  let mut t0: u64 = 0;
-  let mut count = 0;
-  for result in reader.deserialize() {
-    let record = match result as Result<CSVRecord, csv::Error> {
-      Ok (r) => r,
-      Err (err) => {
-        warn!("error processing record: {}", err);
-        continue
-      }, 
+  let batch_size = rayon::current_num_threads();
+  thread::scope(|scope| {
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel::<Result<RawChunk>>(batch_size * 2);
+    let (inputs, headers, record_reader) = (&inputs, &headers, &record_reader);
+    scope.spawn(move || read_inputs(reader, inputs, headers, chunk_tx));
+
+    let (batch_txs, writers): (Vec<SyncSender<Batch>>, Vec<_>) = outputs.into_iter().map(|tiles| {
+      let (tx, rx) = mpsc::sync_channel::<Batch>(2);
+      (tx, scope.spawn(move || write_tiles(tiles, rx)))
+    }).unzip();
+
+    let result = (|| -> Result<()> {
+      let mut t0 = None;
+      let mut count = 0;
+      // dense ids of hashed user ids, in the order they first appear
+      let mut users: HashMap<Vec<u8>, u32> = HashMap::new();
+      let mut chunks = chunk_rx.iter();
+      loop {
+        let batch: Vec<RawChunk> = chunks.by_ref().take(batch_size).collect::<Result<_>>()?;
+        if batch.is_empty() {
+          if record_reader.hashed_users() {
+            info!("Gave ids to {} users", users.len());
+            write_users(&format!("{}_users.bin", output_prefix), mem::take(&mut users))?;
+          }
+          return Ok(());
+        }
+        let mut parsed: Vec<ParsedChunk> = batch.par_iter()
+          .map(|(input, line, data)| {
+            parse_chunk(data, input_name(&inputs[*input]), *line, headers, record_reader, grid, n_tiles)
+          })
+          .collect();
+        if record_reader.hashed_users() {
+          intern_users(&mut parsed, &mut users)?;
+        }
+        for chunk in parsed.iter() {
+          if count / 1000000 != (count + chunk.records) / 1000000 {
+            info!("Processed {} records", count + chunk.records);
+          }
+          count += chunk.records;
+          t0 = t0.or(chunk.first_ts);
+        }
+        let parsed = Arc::new(parsed);
+        for tx in batch_txs.iter() {
+          // a writer only stops early on an error, which is returned on join
+          if tx.send((t0.unwrap_or(0), parsed.clone())).is_err() {
+            return Ok(());
+          }
+        }
+      }
+    })();
+    drop(batch_txs);
+    // stop the reader if it's still going
+    drop(chunk_rx);
+    let written = writers.into_iter()
+      .map(|w| w.join().expect("tile writer panicked"))
+      .collect::<Result<Vec<_>>>();
+    result.and(written.map(|_| ()))
+  })
+}
+
+// Inputs in the order given, with globs expanded to the files they match in
+// sorted order.
+fn expand_inputs<'a>(patterns: impl Iterator<Item = &'a String>) -> Result<Vec<String>> {
+  let mut inputs = Vec::new();
+  for pattern in patterns {
+    if pattern == "-" || !pattern.contains(['*', '?', '[']) {
+      inputs.push(pattern.clone());
+      continue;
+    }
+    let paths = glob::glob(pattern).map_err(|e| Error::InvalidArgument(format!("{}: {}", pattern, e)))?;
+    let matched = inputs.len();
+    for path in paths {
+      let path = path.map_err(|e| Error::file(e.path())(io::Error::new(e.error().kind(), e.error().to_string())))?;
+      inputs.push(path.display().to_string());
+    }
+    if inputs.len() == matched {
+      return Err(Error::InvalidArgument(format!("no files match {}", pattern)));
+    }
+  }
+  if inputs.iter().filter(|i| *i == "-").count() > 1 {
+    return Err(Error::InvalidArgument(String::from("stdin can only be read once")));
+  }
+  Ok(inputs)
+}
+
+fn input_name(input: &str) -> &str {
+  if input == "-" { "stdin" } else { input }
+}
+
+// Opens a file, or stdin for -, decompressing it if it's gzipped, and reads
+// the header line.
+fn open_input(input: &str) -> Result<(Box<dyn BufRead + Send>, ByteRecord)> {
+  let name = input_name(input);
+  let source: Box<dyn Read + Send> = match input {
+    "-" => Box::new(io::stdin()),
+    _ => Box::new(File::open(input).map_err(Error::file(name))?),
+  };
+  let mut reader = BufReader::with_capacity(CHUNK_SIZE, source);
+  let gzipped = reader.fill_buf().map_err(Error::file(name))?.starts_with(&GZIP_MAGIC);
+  let mut reader: Box<dyn BufRead + Send> = if gzipped {
+    Box::new(BufReader::with_capacity(CHUNK_SIZE, MultiGzDecoder::new(reader)))
+  } else {
+    Box::new(reader)
+  };
+
+  let mut header_line = Vec::new();
+  reader.read_until(b'\n', &mut header_line).map_err(Error::file(name))?;
+  let mut headers = ByteRecord::new();
+  csv::ReaderBuilder::new().has_headers(false).from_reader(&header_line[..]).read_byte_record(&mut headers)?;
+  Ok((reader, headers))
+}
+
+// Sends each input in turn, the first of which is already open. Later inputs
+// must have the same columns as the first.
+fn read_inputs(reader: Box<dyn BufRead + Send>, inputs: &[String], headers: &ByteRecord, tx: SyncSender<Result<RawChunk>>) {
+  let mut reader = Some(reader);
+  for (i, input) in inputs.iter().enumerate() {
+    let name = input_name(input);
+    let opened = match reader.take() {
+      Some(reader) => Ok(reader),
+      None => open_input(input).and_then(|(reader, columns)| if columns == *headers {
+        Ok(reader)
+      } else {
+        Err(Error::SizeMismatch(format!("{} has different columns to {}", name, input_name(&inputs[0]))))
+      }),
     };
-    
-    if count % 1000000 == 0 {
-      info!("Processed {} records", count);
+    if inputs.len() > 1 {
+      info!("Reading {}", name);
+    }
+    let sent = opened.and_then(|reader| read_chunks(reader, i, CHUNK_SIZE, &tx).map_err(Error::file(name)));
+    match sent {
+      Ok(true) => (),
+      Ok(false) => return,
+      Err(e) => {
+        let _ = tx.send(Err(e));
+        return;
+      }
     }
+  }
+}
 
-    if !first {
-      first = true;
-      t0 = record.ts;
-      for tile in headers.iter_mut() {
-        tile.start = t0;
+// Sends the rest of the input in chunks of around chunk_size ending at a
+// record end, with the line number each starts at. Returns false if the
+// chunks are no longer wanted.
+fn read_chunks<R: Read>(
+  mut reader: R, input: usize, chunk_size: usize, tx: &SyncSender<Result<RawChunk>>
+) -> io::Result<bool> {
+  let mut carry = Vec::new();
+  // after the header
+  let mut line = 2;
+  loop {
+    let mut chunk = mem::take(&mut carry);
+    let start = chunk.len();
+    chunk.resize(start + chunk_size, 0);
+    let read = read_full(&mut reader, &mut chunk[start..])?;
+    chunk.truncate(start + read);
+    if read == 0 {
+      return Ok(chunk.is_empty() || tx.send(Ok((input, line, chunk))).is_ok());
+    }
+    if let Some(end) = last_record_end(&chunk) {
+      carry = chunk.split_off(end);
+      let lines = chunk.iter().filter(|b| **b == b'\n').count() as u64;
+      if tx.send(Ok((input, line, chunk))).is_err() {
+        return Ok(false);
       }
+      line += lines;
+    } else {
+      carry = chunk;
     }
-    
-    let ts = (record.ts - t0) as u32;
-    if !record.x2_coordinate.is_none() && !record.y2_coordinate.is_none() {
-      for y in record.y_coordinate..=record.y2_coordinate.unwrap() {
-        for x in record.x_coordinate..=record.x2_coordinate.unwrap() {
-          let tile_x = x / size_tile;
-          let tile_y = y / size_tile;
-          if tile_x >= tiles_x || tile_y >= tiles_y {
-            warn!("position {},{} does not belong to a tile", x, y);
-            continue;
-          }
-          let placement = Placement {
-            ts: ts,
-            uid: record.user_id,
-            x: x - tile_x * size_tile,
-            y: y - tile_y * size_tile,
+  }
+}
+
+// Offset just past the last line end in data that isn't inside a quoted
+// field, where data starts at the start of a record. Doubled quotes inside a
+// field toggle the state twice, so need no special handling.
+fn last_record_end(data: &[u8]) -> Option<usize> {
+  let mut quoted = false;
+  let mut end = None;
+  for (i, b) in data.iter().enumerate() {
+    match b {
+      b'"' => quoted = !quoted,
+      b'\n' if !quoted => end = Some(i + 1),
+      _ => (),
+    }
+  }
+  end
+}
+
+// Reads until buf is full or the end of the file.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+  let mut read = 0;
+  while read < buf.len() {
+    match reader.read(&mut buf[read..]) {
+      Ok(0) => break,
+      Ok(n) => read += n,
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(read)
+}
+
+// Writes the original ids of the users given ids while parsing.
+fn write_users(filename: &str, users: HashMap<Vec<u8>, u32>) -> Result<()> {
+  let mut names = vec![Vec::new(); users.len()];
+  for (name, uid) in users {
+    names[uid as usize] = name;
+  }
+  let fw = File::create(filename).map_err(Error::file(filename))?;
+  write_user_dictionary(&names, &mut BufWriter::new(fw))
+}
+
+// Gives hashed user ids in the chunks the next free id the first time
+// they're seen, in file order.
+fn intern_users(chunks: &mut [ParsedChunk], users: &mut HashMap<Vec<u8>, u32>) -> Result<()> {
+  let mut ids = Vec::with_capacity(chunks.len());
+  for chunk in chunks.iter_mut() {
+    let mut chunk_ids = Vec::with_capacity(chunk.users.len());
+    for user in chunk.users.drain(..) {
+      let next = u32::try_from(users.len()).ok().filter(|id| *id != NO_UID)
+        .ok_or_else(|| Error::InvalidArgument(String::from("too many users to give ids to")))?;
+      chunk_ids.push(*users.entry(user).or_insert(next));
+    }
+    ids.push(chunk_ids);
+  }
+  chunks.par_iter_mut().zip(ids.par_iter()).for_each(|(chunk, ids)| {
+    for (_, placement) in chunk.tiles.iter_mut().flatten() {
+      placement.uid = ids[placement.uid as usize];
+    }
+  });
+  Ok(())
+}
+
+// Errors are reported against first_line, the line in the input the chunk
+// starts at.
+fn parse_chunk(
+  data: &[u8],
+  input: &str,
+  first_line: u64,
+  headers: &ByteRecord,
+  record_reader: &RecordReader,
+  grid: TileGrid,
+  n_tiles: usize
+) -> ParsedChunk {
+  let mut chunk = ParsedChunk {
+    first_ts: None,
+    records: 0,
+    tiles: (0..n_tiles).map(|_| Vec::new()).collect(),
+    users: Vec::new(),
+  };
+  // index in chunk.users of each hashed user id
+  let mut users: HashMap<Vec<u8>, u32> = HashMap::new();
+  let mut reader = csv::ReaderBuilder::new()
+    .has_headers(false)
+    .flexible(true)
+    .from_reader(data);
+  let mut row = ByteRecord::new();
+  // csv miscounts lines ending in \r\n, so count them up to the byte
+  // offset, which can point at the \n ending the previous line
+  let (mut line, mut counted) = (first_line, 0);
+  let mut line_at = |byte: usize| {
+    let byte = cmp::min(byte + 1, data.len());
+    if byte > counted {
+      line += data[counted..byte].iter().filter(|b| **b == b'\n').count() as u64;
+      counted = byte;
+    }
+    line
+  };
+  loop {
+    let line = line_at(reader.position().byte() as usize);
+    match reader.read_byte_record(&mut row) {
+      Ok(true) => (),
+      Ok(false) => break,
+      Err(err) => {
+        warn!("error processing record on line {} of {}: {}", line, input, err);
+        continue
+      }
+    }
+    let line = line_at(row.position().map_or(0, |p| p.byte() as usize));
+    if row.len() != headers.len() {
+      warn!("error processing record on line {} of {}: found {} fields, expected {}", line, input, row.len(), headers.len());
+      continue
+    }
+    let record = match record_reader.read(&row) {
+      Ok(r) => r,
+      Err(err) => {
+        warn!("error processing record on line {} of {}: {}", line, input, err);
+        continue
+      },
+    };
+    chunk.first_ts.get_or_insert(record.ts);
+    let uid = match record.user {
+      InputUser::Id(uid) => uid,
+      InputUser::Hash(hash) => match users.get(hash) {
+        Some(uid) => *uid,
+        None => {
+          chunk.users.push(hash.to_vec());
+          users.insert(hash.to_vec(), chunk.users.len() as u32 - 1);
+          chunk.users.len() as u32 - 1
+        },
+      },
+    };
+
+    let mut push = |x: i64, y: i64, isblk: bool| -> bool {
+      match grid.locate(x, y) {
+        Some((tile_idx, x, y)) => {
+          chunk.tiles[tile_idx].push((record.ts, Placement {
+            ts: 0,
+            uid,
+            x,
+            y,
             color: record.color,
-            isblk: true,
-          };
-          let tile_idx = (tile_y * tiles_x + tile_x) as usize;
-          write_record(&placement, &mut handles[tile_idx]).unwrap();
-          headers[tile_idx].count += 1;
-          headers[tile_idx].uid_count = cmp::max(headers[tile_idx].uid_count, record.user_id);
-        }
+            isblk: isblk as u8,
+          }));
+          true
+        },
+        None => false
+      }
+    };
+    if let InputShape::Pixel = record.shape {
+      if !push(record.x as i64, record.y as i64, false) {
+        continue;
       }
     } else {
-      let tile_x = record.x_coordinate / size_tile;
-      let tile_y = record.y_coordinate / size_tile;
-      if tile_x >= tiles_x || tile_y >= tiles_y {
-        warn!("position {},{} does not belong to a tile", record.x_coordinate, record.y_coordinate);
+      let pixels = record.pixels(grid.bounds());
+      if pixels.is_empty() {
+        warn!("error processing record on line {} of {}: shape covers no pixels of the canvas", line, input);
         continue;
       }
-      let placement = Placement {
-        ts: ts,
-        uid: record.user_id,
-        x: record.x_coordinate - tile_x * size_tile,
-        y: record.y_coordinate - tile_y * size_tile,
-        color: record.color,
-        isblk: false,
-      };
-      let tile_idx = (tile_y * tiles_x + tile_x) as usize;
-      write_record(&placement, &mut handles[tile_idx]).unwrap();
-      headers[tile_idx].count += 1;
-      headers[tile_idx].uid_count = cmp::max(headers[tile_idx].uid_count, record.user_id);
+      for (x, y, isblk) in pixels {
+        push(x, y, isblk);
+      }
     }
-    count += 1;
+    chunk.records += 1;
   }
+  chunk
+}
 
-  for ty in 0..tiles_y {
-    for tx in 0..tiles_x {
-      let tile_idx = (tx + ty * tiles_x) as usize;
-      let handle = &mut handles[tile_idx];
-      handle.seek(SeekFrom::Start(0)).unwrap();
-      write_record(&headers[tile_idx], &mut handles[tile_idx]).unwrap();
+// Writes each batch's placements for the tiles, then their headers once the
+// batches run out.
+fn write_tiles(mut tiles: Vec<TileOutput>, rx: Receiver<Batch>) -> Result<()> {
+  let mut t0 = 0;
+  for (start, batch) in rx.iter() {
+    t0 = start;
+    for chunk in batch.iter() {
+      for tile in tiles.iter_mut() {
+        for (ts, placement) in chunk.tiles[tile.index].iter() {
+          let placement = Placement { ts: ts.wrapping_sub(t0) as u32, ..*placement };
+          write_placement(&placement, &mut tile.handle, tile.compact_writer.as_mut())?;
+          tile.header.count += 1;
+          tile.header.max_uid = cmp::max(tile.header.max_uid, placement.uid);
+          if let Some((writer, handle)) = tile.keyframes.as_mut() {
+            writer.push(&placement, handle)?;
+          }
+        }
+      }
     }
   }
 
+  for tile in tiles.iter_mut() {
+    if let Some((writer, mut handle)) = tile.keyframes.take() {
+      writer.finish(&mut handle)?;
+    }
+    let handle = &mut tile.handle;
+    if let Some(w) = tile.compact_writer.as_mut() {
+      w.finish(handle)?;
+    }
+    tile.header.start = t0;
+    handle.seek(SeekFrom::Start(0))?;
+    write_record(&tile.header, handle)?;
+    handle.flush()?;
+  }
   Ok(())
-}
\ No newline at end of file
+}
+fn write_placement(
+  placement: &Placement,
+  handle: &mut BufWriter<File>,
+  compact_writer: Option<&mut CompactWriter>
+) -> Result<()> {
+  match compact_writer {
+    Some(w) => w.push(placement, handle),
+    None => {
+      write_record(placement, handle)?;
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc::sync_channel;
+  use crate::schema::InputSchema;
+
+  const INPUT: &[u8] = b"2022-04-04 00:53:51 UTC,\"a\nb\",#000000,\"1,2\"\n\
+    2022-04-04 00:53:52 UTC,\"c\"\"\n\nd\",#FFFFFF,\"3,4\"\r\n\
+    2022-04-04 00:53:53 UTC,e,#000000,\"5,6\"\n";
+
+  // The placements parsed from each chunk in file order, with the user hash
+  // in place of the chunk's index for it.
+  fn parse(chunks: &[RawChunk]) -> Vec<(u64, Vec<u8>, Placement)> {
+    let headers = ByteRecord::from(vec!["timestamp", "user_id", "pixel_color", "coordinate"]);
+    let reader = RecordReader::new(&InputSchema::Reddit2022.schema(&headers), &headers, Some(&[0xFFFFFF, 0x000000]), 16).unwrap();
+    let grid = TileGrid { size_tile: 16, tiles_x: 1, tiles_y: 1, origin_x: 0, origin_y: 0 };
+    chunks.iter().flat_map(|(_, line, data)| {
+      let chunk = parse_chunk(data, "test", *line, &headers, &reader, grid, 1);
+      chunk.tiles[0].iter().map(|(ts, p)| (*ts, chunk.users[p.uid as usize].clone(), Placement { uid: 0, ..*p })).collect::<Vec<_>>()
+    }).collect()
+  }
+
+  #[test]
+  fn chunks_end_at_records() {
+    assert_eq!(last_record_end(b"1,\"a\nb\"\n2,\"c\n"), Some(8));
+    assert_eq!(last_record_end(b"1,\"a\"\"\nb\"\n2"), Some(10));
+    assert_eq!(last_record_end(b"1,\"a\nb"), None);
+
+    let sequential = parse(&[(0, 2, INPUT.to_vec())]);
+    assert_eq!(sequential.len(), 3);
+    assert_eq!(sequential[1].1, b"c\"\n\nd");
+    for chunk_size in [1, 8, 40, INPUT.len()] {
+      let (tx, rx) = sync_channel(INPUT.len());
+      assert!(read_chunks(INPUT, 0, chunk_size, &tx).unwrap());
+      drop(tx);
+      let chunks: Vec<RawChunk> = rx.into_iter().map(Result::unwrap).collect();
+      assert_eq!(parse(&chunks), sequential, "chunks of {}", chunk_size);
+    }
+  }
+}
@@ -0,0 +1,29 @@
+use clap::Parser;
+
+use crate::commands::IndexArgs;
+use crate::error::Result;
+use crate::store::index::write_tile_index;
+use crate::models::record::{TilePixelIndexHeader, TILE_PIXEL_INDEX_VERSION_ID};
+
+#[derive(Parser)]
+pub struct PixelIndexCommand {
+  #[clap(flatten)]
+  args: IndexArgs,
+}
+
+impl PixelIndexCommand {
+  pub fn execute(&self) -> Result<()> {
+    self.args.each("pixidx", |tile, out_path| {
+      let header = TilePixelIndexHeader {
+        version: TILE_PIXEL_INDEX_VERSION_ID,
+        flags: 0,
+        size: tile.size,
+        start_x: tile.start_x,
+        start_y: tile.start_y,
+        count: tile.count,
+      };
+      let size = tile.size as usize;
+      write_tile_index(tile, &header, size * size, |p| p.x as usize + p.y as usize * size, out_path)
+    })
+  }
+}
@@ -2,17 +2,22 @@ use actix_web::{error, get, middleware, web, App, HttpServer, HttpResponse, Resp
 use actix_web::http::header::ContentType;
 use clap::Parser;
 use log::info;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
-use std::fs::read_to_string;
 use tokio::runtime::Runtime;
 
+use crate::image::{upscale, write_animation, write_image};
 use crate::store::config::ConfigRoot;
-use crate::store::dataset::Dataset;
+use crate::store::dataset::{timelapse_frames, Dataset, PYRAMID_TILE_SIZE};
 use crate::store::tile::Tile;
 
 const INITIAL_IMAGE_SIZE: usize = 8192;
 const CACHE_CONTROL_VALUE: &str = "max-age=2678400";
+const MAX_TIMELAPSE_FRAMES: u32 = 500;
+const TIMELAPSE_FRAME_DELAY: u16 = 100;
+const MAX_SCALE: u16 = 16;
+const MAX_IMAGE_SIDE: u32 = 8192;
 
 #[derive(Parser)]
 pub struct ServeCommand {
@@ -30,25 +35,67 @@ pub struct ServeCommand {
 }
 
 type DatasetsMapArc = Arc<HashMap<String, Dataset>>;
+#[derive(Deserialize)]
+struct ImageQuery {
+  // Integer nearest-neighbour upscaling factor
+  scale: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct PixelHistory {
+  // input coordinates of the pixel
+  x: i32,
+  y: i32,
+  placements: Vec<PixelPlacement>,
+}
+
+#[derive(Serialize)]
+struct PixelPlacement {
+  ts: u64,
+  uid: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  user_hash: Option<String>,
+  color: u8,
+  isblk: bool,
+}
+
+#[derive(Serialize)]
+struct UserPixels {
+  uid: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  user_hash: Option<String>,
+  tiles: Vec<UserTilePixels>,
+}
+
+#[derive(Serialize)]
+struct UserTilePixels {
+  tile_x: u16,
+  tile_y: u16,
+  // canvas coordinates as [x, y]
+  pixels: Vec<(u16, u16)>,
+}
+
+type RegionTimelapsePath = (String, i32, i32, i32, i32, u64, u64, u64);
 
 impl ServeCommand {
-  pub fn execute(&self) {
-    let config_str = read_to_string(&self.config_file).unwrap();
-    let config: ConfigRoot = serde_yaml::from_str(&config_str).unwrap();
+  pub fn execute(&self) -> crate::error::Result<()> {
+    let config = ConfigRoot::load(&self.config_file)?;
     let mut datasets: HashMap<String, Dataset> = HashMap::new();
     
     for serialized_dataset in config.datasets.iter() {
       if datasets.contains_key(&serialized_dataset.name) {
-        panic!("Dataset {} already exists in map", serialized_dataset.name);
+        return Err(crate::error::Error::Config(format!(
+          "dataset {} already exists in map", serialized_dataset.name
+        )));
       }
-      let dataset: Dataset = serialized_dataset.load();
+      let dataset: Dataset = serialized_dataset.load()?;
       datasets.insert(serialized_dataset.name.clone(), dataset);
     }
 
     // create http server
-    let rt = Runtime::new().unwrap();
-    rt.block_on(server(&self.host, self.port, Arc::new(datasets)))
-      .unwrap();
+    let rt = Runtime::new()?;
+    rt.block_on(server(&self.host, self.port, Arc::new(datasets)))?;
+    Ok(())
   }
 }
 
@@ -63,9 +110,18 @@ async fn server(host: &str, port: u16, datasets: Arc<HashMap<String, Dataset>>)
           .add(("x-content-type-options", "nosniff"))
         )
         .service(get_image_by_timestamp)
+        .service(get_canvas_by_timestamp)
+        .service(get_region_by_timestamp)
+        .service(get_region_timelapse)
+        .service(get_pyramid_tile_by_timestamp)
         .service(get_image_by_timestamp_diff)
         .service(get_image_by_user_id)
         .service(get_image_by_user_id_remainder)
+        .service(get_tile_timelapse)
+        .service(get_pixel_history)
+        .service(get_user_stats)
+        .service(get_user_pixels)
+        .service(get_canvas_by_user_id)
   })
   .bind((host, port))?
   .run()
@@ -76,16 +132,94 @@ async fn server(host: &str, port: u16, datasets: Arc<HashMap<String, Dataset>>)
 async fn get_image_by_timestamp(
   datasets: web::Data<DatasetsMapArc>,
   path: web::Path<(String, u16, u16, u64)>,
+  query: web::Query<ImageQuery>,
 ) -> Result<impl Responder, error::Error> {
   let (name, tile_x, tile_y, timestamp) = path.into_inner();
-  let (dataset, tile) = get_tile(&datasets, name, tile_x, tile_y).await?;
+  let (dataset, tile) = get_tile(&datasets, name.clone(), tile_x, tile_y).await?;
+
+  let render_tile = move |d: &Dataset| d.get_tile(tile_x, tile_y)?.get_image_at_timestamp(timestamp);
+  let image: Vec<u8> = match render(&datasets, name, render_tile).await? {
+    Some(t) => t,
+    None => return Err(error::ErrorNotFound("timestamp not found"))
+  };
+  image_response(dataset, tile.size, tile.size, &image, &query)
+}
+
+#[get("/images/{name}/canvas/ts/{timestamp}.png")]
+async fn get_canvas_by_timestamp(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<(String, u64)>,
+  query: web::Query<ImageQuery>,
+) -> Result<impl Responder, error::Error> {
+  let (name, timestamp) = path.into_inner();
+  let dataset = get_dataset(&datasets, name.clone()).await?;
+
+  let image = match render(&datasets, name, move |d| d.get_image_at_timestamp(timestamp)).await? {
+    Some(t) => t,
+    None => return Err(error::ErrorNotFound("timestamp not found"))
+  };
+  image_response(dataset, dataset.size_x, dataset.size_y, &image, &query)
+}
+
+#[get("/images/{name}/canvas/uid/{user_id}.png")]
+async fn get_canvas_by_user_id(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<(String, String)>,
+  query: web::Query<ImageQuery>,
+) -> Result<impl Responder, error::Error> {
+  let (name, user) = path.into_inner();
+  let dataset = get_dataset(&datasets, name.clone()).await?;
+  let user_id = get_user_id(dataset, &user)?;
+
+  let image = match render(&datasets, name, move |d| d.get_image_for_user(user_id)).await? {
+    Some(t) => t,
+    None => return Err(error::ErrorNotFound("user id not found"))
+  };
+  image_response(dataset, dataset.size_x, dataset.size_y, &image, &query)
+}
+
+#[get("/images/{name}/region/{x0}/{y0}/{x1}/{y1}/ts/{timestamp}.png")]
+async fn get_region_by_timestamp(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<(String, i32, i32, i32, i32, u64)>,
+  query: web::Query<ImageQuery>,
+) -> Result<impl Responder, error::Error> {
+  let (name, x0, y0, x1, y1, timestamp) = path.into_inner();
+  let dataset = get_dataset(&datasets, name.clone()).await?;
+  let (x0, y0, x1, y1) = get_region(dataset, x0, y0, x1, y1)?;
 
-  let image: Vec<u8> = match tile.get_image_at_timestamp(timestamp) {
-    Some(t) => t.iter().map(|v| (v & 0xff) as u8).collect(),
+  let image = match render(&datasets, name, move |d| d.render_region(x0, y0, x1, y1, timestamp)).await? {
+    Some(t) => t,
     None => return Err(error::ErrorNotFound("timestamp not found"))
   };
+  image_response(dataset, x1 - x0, y1 - y0, &image, &query)
+}
+
+#[get("/images/{name}/region/{x0}/{y0}/{x1}/{y1}/timelapse/{from}_{to}_{step}.png")]
+async fn get_region_timelapse(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<RegionTimelapsePath>,
+) -> Result<impl Responder, error::Error> {
+  let (name, x0, y0, x1, y1, from, to, step) = path.into_inner();
+  let dataset = get_dataset(&datasets, name.clone()).await?;
+  let (x0, y0, x1, y1) = get_region(dataset, x0, y0, x1, y1)?;
+  timelapse(&datasets, name, x0, y0, x1, y1, from, to, step).await
+}
+
+#[get("/images/{name}/zoom/{z}/{x}/{y}/ts/{timestamp}.png")]
+async fn get_pyramid_tile_by_timestamp(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<(String, u32, u32, u32, u64)>,
+) -> Result<impl Responder, error::Error> {
+  let (name, z, x, y, timestamp) = path.into_inner();
+  let dataset = get_dataset(&datasets, name.clone()).await?;
+
+  let image = match render(&datasets, name, move |d| d.render_pyramid_tile(z, x, y, timestamp)).await? {
+    Some(t) => t,
+    None => return Err(error::ErrorNotFound("tile or timestamp not found"))
+  };
   let mut imgdata: Vec<u8> = Vec::with_capacity(INITIAL_IMAGE_SIZE);
-  write_image(tile.size, &image, &dataset.palette, &dataset.trns_palette, &mut imgdata);
+  write_image(PYRAMID_TILE_SIZE, PYRAMID_TILE_SIZE, &image, &dataset.palette, &dataset.trns_palette, &mut imgdata)?;
   Ok(HttpResponse::Ok()
     .content_type(ContentType(mime::IMAGE_PNG))
     .append_header(("cache-control", CACHE_CONTROL_VALUE))
@@ -96,83 +230,228 @@ async fn get_image_by_timestamp(
 async fn get_image_by_timestamp_diff(
   datasets: web::Data<DatasetsMapArc>,
   path: web::Path<(String, u16, u16, u64, u64)>,
+  query: web::Query<ImageQuery>,
 ) -> Result<impl Responder, error::Error> {
   let (name, tile_x, tile_y, timestamp1, timestamp2) = path.into_inner();
-  let (dataset, tile) = get_tile(&datasets, name, tile_x, tile_y).await?;
+  let (dataset, tile) = get_tile(&datasets, name.clone(), tile_x, tile_y).await?;
 
-  let image: Vec<u8> = match tile.get_diff_for_timestamps(timestamp1, timestamp2) {
-    Some(t) => t.iter().map(|v| (v & 0xff) as u8).collect(),
+  let render_diff = move |d: &Dataset| d.get_tile(tile_x, tile_y)?.get_diff_for_timestamps(timestamp1, timestamp2);
+  let image: Vec<u8> = match render(&datasets, name, render_diff).await? {
+    Some(t) => t,
     None => return Err(error::ErrorNotFound("both timestamps not found"))
   };
-  let mut imgdata: Vec<u8> = Vec::with_capacity(INITIAL_IMAGE_SIZE);
-  write_image(tile.size, &image, &dataset.palette, &dataset.trns_palette, &mut imgdata);
-  Ok(HttpResponse::Ok()
-    .content_type(ContentType(mime::IMAGE_PNG))
-    .append_header(("cache-control", CACHE_CONTROL_VALUE))
-    .body(imgdata))
+  image_response(dataset, tile.size, tile.size, &image, &query)
 }
 
 #[get("/images/{name}/tiles/{tile_x}/{tile_y}/uid-rem/{user_id}_{timestamp}.png")]
 async fn get_image_by_user_id_remainder(
   datasets: web::Data<DatasetsMapArc>,
-  path: web::Path<(String, u16, u16, u32, u64)>,
+  path: web::Path<(String, u16, u16, String, u64)>,
+  query: web::Query<ImageQuery>,
 ) -> Result<impl Responder, error::Error> {
-  let (name, tile_x, tile_y, user_id, timestamp) = path.into_inner();
-  let (dataset, tile) = get_tile(&datasets, name, tile_x, tile_y).await?;
+  let (name, tile_x, tile_y, user, timestamp) = path.into_inner();
+  let (dataset, tile) = get_tile(&datasets, name.clone(), tile_x, tile_y).await?;
+  let user_id = get_user_id(dataset, &user)?;
+  if !tile.has_user_remainders() {
+    return Err(error::ErrorNotImplemented("needs keyframes built with --uids, or the user and pixel indexes"));
+  }
 
-  let image: Vec<u8> = match tile.get_image_at_timestamp(timestamp) {
-    Some(t) => t.iter().map(|v| if (v >> 8) == user_id { v & 0xff } else { 0 } as u8).collect(),
+  let render_remainder = move |d: &Dataset| d.get_tile(tile_x, tile_y)?.get_image_for_user_at_timestamp(user_id, timestamp);
+  let image: Vec<u8> = match render(&datasets, name, render_remainder).await? {
+    Some(t) => t,
     None => return Err(error::ErrorNotFound("timestamp not found"))
   };
-  let mut imgdata: Vec<u8> = Vec::with_capacity(INITIAL_IMAGE_SIZE);
-  write_image(tile.size, &image, &dataset.palette, &dataset.trns_palette, &mut imgdata);
-  Ok(HttpResponse::Ok()
-    .content_type(ContentType(mime::IMAGE_PNG))
-    .append_header(("cache-control", CACHE_CONTROL_VALUE))
-    .body(imgdata))
+  image_response(dataset, tile.size, tile.size, &image, &query)
+}
+
+#[get("/images/{name}/tiles/{tile_x}/{tile_y}/timelapse/{from}_{to}_{step}.png")]
+async fn get_tile_timelapse(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<(String, u16, u16, u64, u64, u64)>,
+) -> Result<impl Responder, error::Error> {
+  let (name, tile_x, tile_y, from, to, step) = path.into_inner();
+  let (_, tile) = get_tile(&datasets, name.clone(), tile_x, tile_y).await?;
+  let (x0, y0, size) = (tile.start_x, tile.start_y, tile.size);
+  timelapse(&datasets, name, x0, y0, x0 + size, y0 + size, from, to, step).await
 }
 
 #[get("/images/{name}/tiles/{tile_x}/{tile_y}/uid/{user_id}.png")]
 async fn get_image_by_user_id(
   datasets: web::Data<DatasetsMapArc>,
-  path: web::Path<(String, u16, u16, u32)>,
+  path: web::Path<(String, u16, u16, String)>,
+  query: web::Query<ImageQuery>,
 ) -> Result<impl Responder, error::Error> {
-  let (name, tile_x, tile_y, user_id) = path.into_inner();
-  let (dataset, tile) = get_tile(&datasets, name, tile_x, tile_y).await?;
+  let (name, tile_x, tile_y, user) = path.into_inner();
+  let (dataset, tile) = get_tile(&datasets, name.clone(), tile_x, tile_y).await?;
+  let user_id = get_user_id(dataset, &user)?;
 
-  let image: Vec<u8> = match tile.get_image_for_user(user_id) {
-    Some(t) => t.iter().map(|v| (v & 0xff) as u8).collect(),
+  let render_user = move |d: &Dataset| d.get_tile(tile_x, tile_y)?.get_image_for_user(user_id);
+  let image: Vec<u8> = match render(&datasets, name, render_user).await? {
+    Some(t) => t,
     None => return Err(error::ErrorNotFound("user id not found"))
   };
+  image_response(dataset, tile.size, tile.size, &image, &query)
+}
+
+#[get("/pixels/{name}/{x}/{y}/history.json")]
+async fn get_pixel_history(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<(String, i32, i32)>,
+) -> Result<impl Responder, error::Error> {
+  let (name, x, y) = path.into_inner();
+  let dataset = get_dataset(&datasets, name).await?;
+
+  let history = dataset.canvas_position(x, y).and_then(|(cx, cy)| dataset.get_pixel_history(cx, cy));
+  let (tile, placements) = match history {
+    Some(h) => h,
+    None => return Err(error::ErrorNotFound("pixel not found"))
+  };
+  Ok(HttpResponse::Ok()
+    .append_header(("cache-control", CACHE_CONTROL_VALUE))
+    .json(PixelHistory {
+      x,
+      y,
+      placements: placements.iter().map(|p| PixelPlacement {
+        ts: tile.start + p.ts as u64,
+        uid: p.uid,
+        user_hash: dataset.user_hash(p.uid),
+        color: p.color,
+        isblk: p.is_blk(),
+      }).collect(),
+    }))
+}
+
+#[get("/users/{name}/{user_id}/stats.json")]
+async fn get_user_stats(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<(String, String)>,
+) -> Result<impl Responder, error::Error> {
+  let (name, user) = path.into_inner();
+  let dataset = get_dataset(&datasets, name).await?;
+  let user_id = get_user_id(dataset, &user)?;
+
+  Ok(HttpResponse::Ok()
+    .append_header(("cache-control", CACHE_CONTROL_VALUE))
+    .json(dataset.get_user_stats(user_id)))
+}
+
+#[get("/users/{name}/{user_id}/pixels.json")]
+async fn get_user_pixels(
+  datasets: web::Data<DatasetsMapArc>,
+  path: web::Path<(String, String)>,
+) -> Result<impl Responder, error::Error> {
+  let (name, user) = path.into_inner();
+  let dataset = get_dataset(&datasets, name).await?;
+  let user_id = get_user_id(dataset, &user)?;
+
+  Ok(HttpResponse::Ok()
+    .append_header(("cache-control", CACHE_CONTROL_VALUE))
+    .json(UserPixels {
+      uid: user_id,
+      user_hash: dataset.user_hash(user_id),
+      tiles: dataset.get_user_pixels(user_id).into_iter().map(|(tile, pixels)| UserTilePixels {
+        tile_x: tile.start_x / dataset.size_tile,
+        tile_y: tile.start_y / dataset.size_tile,
+        pixels,
+      }).collect(),
+    }))
+}
+
+async fn get_dataset(datasets: &DatasetsMapArc, name: String) -> Result<&Dataset, error::Error> {
+  match datasets.get(&name) {
+    Some(d) => Ok(d),
+    None => Err(error::ErrorNotFound("dataset not found"))
+  }
+}
+
+// Integer id of a user given by either id, or their original id if ids were
+// given while parsing.
+fn get_user_id(dataset: &Dataset, user: &str) -> Result<u32, error::Error> {
+  match dataset.resolve_user(user) {
+    Some(id) => Ok(id),
+    None => Err(error::ErrorNotFound("user id not found"))
+  }
+}
+
+// Canvas coordinates of a region given in input coordinates.
+fn get_region(dataset: &Dataset, x0: i32, y0: i32, x1: i32, y1: i32) -> Result<(u16, u16, u16, u16), error::Error> {
+  match dataset.canvas_region(x0, y0, x1, y1) {
+    Some(region) => Ok(region),
+    None => Err(error::ErrorBadRequest("invalid region"))
+  }
+}
+
+async fn get_tile(datasets: &DatasetsMapArc, name: String, tile_x: u16, tile_y: u16) -> Result<(&Dataset, &Tile), error::Error> {
+  let dataset = get_dataset(datasets, name).await?;
+
+  match dataset.get_tile(tile_x, tile_y) {
+    Some(t) => Ok((dataset, t)),
+    None => Err(error::ErrorNotFound("tile not found"))
+  }
+}
+
+fn image_response(
+  dataset: &Dataset, width: u16, height: u16, image: &[u8], query: &ImageQuery
+) -> Result<HttpResponse, error::Error> {
+  let scale = query.scale.unwrap_or(1);
+  if scale == 0 || scale > MAX_SCALE ||
+    width as u32 * scale as u32 > MAX_IMAGE_SIDE || height as u32 * scale as u32 > MAX_IMAGE_SIDE {
+    return Err(error::ErrorBadRequest("invalid scale"));
+  }
+
   let mut imgdata: Vec<u8> = Vec::with_capacity(INITIAL_IMAGE_SIZE);
-  write_image(tile.size, &image, &dataset.palette, &dataset.trns_palette, &mut imgdata);
+  if scale > 1 {
+    let scaled = upscale(image, width, height, scale);
+    write_image(width * scale, height * scale, &scaled, &dataset.palette, &dataset.trns_palette, &mut imgdata)?;
+  } else {
+    write_image(width, height, image, &dataset.palette, &dataset.trns_palette, &mut imgdata)?;
+  }
   Ok(HttpResponse::Ok()
     .content_type(ContentType(mime::IMAGE_PNG))
     .append_header(("cache-control", CACHE_CONTROL_VALUE))
     .body(imgdata))
 }
 
-async fn get_tile(datasets: &DatasetsMapArc, name: String, tile_x: u16, tile_y: u16) -> Result<(&Dataset, &Tile), error::Error> {
-  let dataset = match datasets.get(&name) {
-    Some(d) => d,
-    None => {
-      return Err(error::ErrorNotFound("dataset not found"));
-    }
-  };
+// Runs a render against the named dataset, which the caller has checked
+// exists, on the blocking thread pool so replaying tiles doesn't hold up the
+// server's workers.
+async fn render<R: Send + 'static, F: FnOnce(&Dataset) -> R + Send + 'static>(
+  datasets: &DatasetsMapArc, name: String, render: F
+) -> Result<R, error::Error> {
+  let datasets = datasets.clone();
+  Ok(web::block(move || render(&datasets[&name])).await?)
+}
 
-  match dataset.get_tile(tile_x, tile_y) {
-    Some(t) => return Ok((dataset, t)),
-    None => {
-      return Err(error::ErrorNotFound("tile not found"));
-    }
+// Renders the frames of a timelapse, of which there can be hundreds.
+#[allow(clippy::too_many_arguments)]
+async fn timelapse(
+  datasets: &DatasetsMapArc, name: String, x0: u16, y0: u16, x1: u16, y1: u16, from: u64, to: u64, step: u64
+) -> Result<HttpResponse, error::Error> {
+  let count = match timelapse_frames(from, to, step) {
+    Some(count) if count <= MAX_TIMELAPSE_FRAMES as u64 => count as u32,
+    Some(_) => return Err(error::ErrorBadRequest("too many frames")),
+    None => return Err(error::ErrorBadRequest("invalid timestamps")),
   };
-}
 
-fn write_image<T: std::io::Write>(size: u16, data: &[u8], palette: &[u8], trns_palette: &[u8],  w: T) {
-  let mut encoder = png::Encoder::new(w, size as u32, size as u32);
-  encoder.set_color(png::ColorType::Indexed);
-  encoder.set_palette(palette);
-  encoder.set_trns(trns_palette);
-  let mut writer = encoder.write_header().unwrap();
-  writer.write_image_data(&data).unwrap();
+  let imgdata = render(datasets, name, move |dataset| -> std::io::Result<Option<Vec<u8>>> {
+    let replay = match dataset.replay_region(x0, y0, x1, y1, from) {
+      Some(r) => r,
+      None => return Ok(None)
+    };
+    let (width, height) = (replay.width(), replay.height());
+    let mut imgdata: Vec<u8> = Vec::with_capacity(INITIAL_IMAGE_SIZE);
+    write_animation(
+      width, height, TIMELAPSE_FRAME_DELAY, replay.timelapse(from, step, count),
+      &dataset.palette, &dataset.trns_palette, &mut imgdata
+    )?;
+    Ok(Some(imgdata))
+  }).await??;
+
+  match imgdata {
+    Some(imgdata) => Ok(HttpResponse::Ok()
+      .content_type(ContentType(mime::IMAGE_PNG))
+      .append_header(("cache-control", CACHE_CONTROL_VALUE))
+      .body(imgdata)),
+    None => Err(error::ErrorNotFound("timestamp not found"))
+  }
 }
@@ -0,0 +1,93 @@
+use clap::Parser;
+use log::info;
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::error::{Error, Result};
+use crate::image::write_animation;
+use crate::store::config::ConfigRoot;
+use crate::store::dataset::timelapse_frames;
+
+#[derive(Parser)]
+pub struct TimelapseCommand {
+  // Dataset config
+  #[clap(required=true)]
+  config_file: String,
+
+  // Name of the dataset
+  #[clap(required=true)]
+  name: String,
+
+  // First timestamp, unix timestamp in milliseconds
+  #[clap(required=true)]
+  from: u64,
+
+  // Last timestamp, unix timestamp in milliseconds
+  #[clap(required=true)]
+  to: u64,
+
+  // Time between frames in milliseconds
+  #[clap(required=true)]
+  step: u64,
+
+  // Output APNG
+  #[clap(required=true)]
+  output: String,
+
+  // Region of the canvas to render, defaults to the whole canvas
+  #[clap(long, number_of_values=4, value_names=&["X0", "Y0", "X1", "Y1"], conflicts_with="tile")]
+  region: Option<Vec<u16>>,
+
+  // Tile to render instead of a region
+  #[clap(long, number_of_values=2, value_names=&["TILE_X", "TILE_Y"])]
+  tile: Option<Vec<u16>>,
+
+  // Delay between frames in the animation in milliseconds
+  #[clap(long, default_value_t = 100)]
+  delay: u16,
+}
+
+impl TimelapseCommand {
+  pub fn execute(&self) -> Result<()> {
+    let count = timelapse_frames(self.from, self.to, self.step)
+      .and_then(|count| u32::try_from(count).ok())
+      .ok_or_else(|| Error::InvalidArgument(String::from(
+        "timestamps must be increasing with a non-zero step and fewer than 2^32 frames"
+      )))?;
+    let config = ConfigRoot::load(&self.config_file)?;
+    let dataset = config.dataset(&self.name)?.load()?;
+
+    let (x0, y0, x1, y1) = match (&self.region, &self.tile) {
+      (Some(r), _) => (r[0], r[1], r[2], r[3]),
+      (None, Some(t)) => {
+        let tile = match dataset.get_tile(t[0], t[1]) {
+          Some(tile) => tile,
+          None => return Err(Error::InvalidArgument(format!("tile {},{} is outside the canvas", t[0], t[1])))
+        };
+        (tile.start_x, tile.start_y, tile.start_x + tile.size, tile.start_y + tile.size)
+      },
+      (None, None) => (0, 0, dataset.size_x, dataset.size_y),
+    };
+
+    let replay = match dataset.replay_region(x0, y0, x1, y1, self.from) {
+      Some(r) => r,
+      None => return Err(Error::InvalidArgument(format!(
+        "unable to render region {},{} {},{} at {}", x0, y0, x1, y1, self.from
+      )))
+    };
+    info!("Writing {} frames of {}x{} to {}", count, replay.width(), replay.height(), self.output);
+
+    let w = BufWriter::new(File::create(&self.output).map_err(Error::file(&self.output))?);
+    let (width, height) = (replay.width(), replay.height());
+    let frames = replay.timelapse(self.from, self.step, count)
+      .enumerate()
+      .map(|(i, frame)| {
+        if i % 100 == 0 {
+          info!("Rendered {}/{} frames", i, count);
+        }
+        frame
+      });
+    write_animation(width, height, self.delay, frames, &dataset.palette, &dataset.trns_palette, w)?;
+    Ok(())
+  }
+}
@@ -0,0 +1,111 @@
+use clap::Parser;
+use log::{info, warn};
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::commands::keyframe::{spill_path, write_keyframes, KeyframeOptions};
+use crate::commands::sidecar_path;
+use crate::error::{Error, Result};
+use crate::models::FrameEncoding;
+use crate::models::record::{
+  write_record, Header, TileKeyframeHeader, TilePixelIndexHeader, TilePlacementHeader,
+  TileUserIndexHeader, FORMAT_COLOUR_FRAMES, FORMAT_VERSION, FRAME_FLAG_ZSTD
+};
+use crate::store::tile::{read_header, Tile};
+
+#[derive(Parser)]
+pub struct UpgradeCommand {
+  // Tile placement logs to upgrade, along with their keyframes and indexes
+  #[clap(required=true, min_values=1)]
+  inputs: Vec<String>,
+}
+
+impl UpgradeCommand {
+  pub fn execute(&self) -> Result<()> {
+    for input in self.inputs.iter() {
+      upgrade_file::<TilePlacementHeader>(input, Path::new(input))?;
+      for (kind, upgrade) in [
+        ("frame", upgrade_keyframes as fn(&str, &Path) -> Result<()>),
+        ("pixidx", upgrade_file::<TilePixelIndexHeader>),
+        ("uididx", upgrade_file::<TileUserIndexHeader>),
+      ] {
+        match sidecar_path(input, kind) {
+          Some(path) if path.exists() => upgrade(input, &path)?,
+          Some(_) => (),
+          None => warn!("unable to match filename for {}", input),
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+// Opens the file and reads its header, returning it with its format and
+// size.
+fn open_with_header<H: Header + Debug>(path: &Path) -> Result<(File, H, u16, usize)> {
+  let mut file = File::open(path).map_err(Error::file(path))?;
+  let mut buf = Vec::new();
+  (&mut file).take(H::SIZE.max(H::LEGACY_SIZE) as u64).read_to_end(&mut buf)
+    .map_err(Error::file(path))?;
+  let (header, format, offset) = read_header::<H>(&path.display().to_string(), &buf)?;
+  Ok((file, header, format, offset))
+}
+
+// Writes the replacement for the file alongside then renames it over, so the
+// original is left intact on failure.
+fn replace_file<F: FnOnce(&mut BufWriter<File>) -> Result<()>>(path: &Path, write: F) -> Result<()> {
+  let mut tmp_path = PathBuf::from(path);
+  tmp_path.set_extension("bin.tmp");
+  let result = (|| -> Result<()> {
+    let mut w = BufWriter::new(File::create(&tmp_path).map_err(Error::file(&tmp_path))?);
+    write(&mut w)?;
+    w.flush()?;
+    fs::rename(&tmp_path, path).map_err(Error::file(path))
+  })();
+  if result.is_err() {
+    let _ = fs::remove_file(&tmp_path);
+  }
+  result
+}
+
+// Rewrites the file with its header in the current format. The data after
+// the header is unchanged between formats so is copied as is.
+fn upgrade_file<H: Header + Debug>(_input: &str, path: &Path) -> Result<()> {
+  let filename = path.display().to_string();
+  let (mut file, header, format, offset) = open_with_header::<H>(path)?;
+  if format == FORMAT_VERSION {
+    info!("{} is already format {}", filename, format);
+    return Ok(());
+  }
+
+  replace_file(path, |w| {
+    write_record(&header, w)?;
+    file.seek(SeekFrom::Start(offset as u64))?;
+    io::copy(&mut file, w)?;
+    Ok(())
+  })?;
+  info!("upgraded {} from format {} to {}", filename, format, FORMAT_VERSION);
+  Ok(())
+}
+
+// Keyframes before format 3 packed uids and colours together, so are
+// rebuilt from the already upgraded log with both planes and the same
+// interval and encoding.
+fn upgrade_keyframes(input: &str, path: &Path) -> Result<()> {
+  let (_, header, format, _) = open_with_header::<TileKeyframeHeader>(path)?;
+  if format >= FORMAT_COLOUR_FRAMES {
+    return upgrade_file::<TileKeyframeHeader>(input, path);
+  }
+
+  let tile = Tile::load(input)?;
+  let encoding = if header.flags & FRAME_FLAG_ZSTD != 0 { FrameEncoding::Zstd } else { FrameEncoding::Raw };
+  replace_file(path, |w| {
+    let options = KeyframeOptions { interval: header.interval, encoding, uids: true, by_time: false, max_replay: None };
+    write_keyframes(&tile, w, &spill_path(path), options)?;
+    Ok(())
+  })?;
+  info!("rebuilt {} from format {} to {}", path.display(), format, FORMAT_VERSION);
+  Ok(())
+}
@@ -0,0 +1,29 @@
+use clap::Parser;
+
+use crate::commands::IndexArgs;
+use crate::error::Result;
+use crate::store::index::write_tile_index;
+use crate::models::record::{TileUserIndexHeader, TILE_USER_INDEX_VERSION_ID};
+
+#[derive(Parser)]
+pub struct UserIndexCommand {
+  #[clap(flatten)]
+  args: IndexArgs,
+}
+
+impl UserIndexCommand {
+  pub fn execute(&self) -> Result<()> {
+    self.args.each("uididx", |tile, out_path| {
+      let header = TileUserIndexHeader {
+        version: TILE_USER_INDEX_VERSION_ID,
+        flags: 0,
+        size: tile.size,
+        start_x: tile.start_x,
+        start_y: tile.start_y,
+        count: tile.count,
+        max_uid: tile.max_uid,
+      };
+      write_tile_index(tile, &header, tile.max_uid as usize + 1, |p| p.uid as usize, out_path)
+    })
+  }
+}
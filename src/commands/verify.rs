@@ -0,0 +1,43 @@
+use clap::Parser;
+use log::{error, info};
+
+use crate::error::{Error, Result};
+use crate::store::config::ConfigRoot;
+
+#[derive(Parser)]
+pub struct VerifyCommand {
+  // Dataset config
+  #[clap(required=true)]
+  config_file: String,
+  // Only verify the named datasets rather than every one in the config
+  #[clap(long)]
+  dataset: Vec<String>,
+}
+
+impl VerifyCommand {
+  pub fn execute(&self) -> Result<()> {
+    let config = ConfigRoot::load(&self.config_file)?;
+    let datasets = match self.dataset.is_empty() {
+      true => config.datasets.iter().collect(),
+      false => self.dataset.iter()
+        .map(|name| config.dataset(name))
+        .collect::<Result<Vec<_>>>()?,
+    };
+
+    let mut total = 0;
+    for dataset in datasets {
+      info!("verifying dataset {}", dataset.name);
+      let problems = dataset.verify()?;
+      for problem in problems.iter() {
+        error!("{}: {}", dataset.name, problem);
+      }
+      total += problems.len();
+    }
+
+    if total > 0 {
+      return Err(Error::Corrupt(format!("found {} problems", total)));
+    }
+    info!("no problems found");
+    Ok(())
+  }
+}
@@ -0,0 +1,73 @@
+use std::{fmt, io};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum Error {
+  Io(io::Error),
+  // IO error on a specific file
+  File(String, io::Error),
+  Csv(csv::Error),
+  // Unreadable config file or a dataset missing from it
+  Config(String),
+  InvalidArgument(String),
+  // File has the wrong version magic for its kind
+  BadVersion { filename: String, expected: u16, found: u16 },
+  // Headers or sizes that don't agree between files, or with the config
+  SizeMismatch(String),
+  // File in a newer format, or using features this build can't read
+  Unsupported(String),
+  // Contents of a file that don't make sense, such as out of order placements
+  Corrupt(String),
+  MissingTile { x: u16, y: u16, filename: String },
+  // Some of the inputs to a command failed, each of which has been logged
+  Failed(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "{}", e),
+      Error::File(filename, e) => write!(f, "{}: {}", filename, e),
+      Error::Csv(e) => write!(f, "csv: {}", e),
+      Error::Config(s) => write!(f, "config: {}", s),
+      Error::InvalidArgument(s) => write!(f, "invalid argument: {}", s),
+      Error::BadVersion { filename, expected, found } => write!(
+        f, "{}: header version is {:#06x}, expected {:#06x}", filename, found, expected
+      ),
+      Error::SizeMismatch(s) => write!(f, "size mismatch: {}", s),
+      Error::Unsupported(s) => write!(f, "unsupported: {}", s),
+      Error::Corrupt(s) => write!(f, "corrupt: {}", s),
+      Error::MissingTile { x, y, filename } => write!(f, "tile {},{} is missing {}", x, y, filename),
+      Error::Failed(s) => write!(f, "{}", s),
+    }
+  }
+}
+
+impl Error {
+  // Wraps an IO error with the file it happened on, for use with map_err.
+  pub fn file<P: AsRef<Path>>(path: P) -> impl FnOnce(io::Error) -> Error {
+    move |e| Error::File(path.as_ref().display().to_string(), e)
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+  fn from(e: io::Error) -> Error {
+    Error::Io(e)
+  }
+}
+
+impl From<csv::Error> for Error {
+  fn from(e: csv::Error) -> Error {
+    Error::Csv(e)
+  }
+}
+
+impl From<serde_yaml::Error> for Error {
+  fn from(e: serde_yaml::Error) -> Error {
+    Error::Config(e.to_string())
+  }
+}
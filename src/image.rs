@@ -0,0 +1,83 @@
+use std::{cmp, iter};
+use std::io::{self, Write};
+
+pub fn write_image<T: Write>(
+  width: u16, height: u16, data: &[u8], palette: &[u8], trns_palette: &[u8], w: T
+) -> io::Result<()> {
+  let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+  encoder.set_color(png::ColorType::Indexed);
+  encoder.set_palette(palette);
+  encoder.set_trns(trns_palette);
+  let mut writer = encoder.write_header()?;
+  writer.write_image_data(data)?;
+  Ok(())
+}
+
+// Writes an APNG with every frame shown for `delay` milliseconds, pulling
+// each frame from `frames` as it is encoded.
+pub fn write_animation<T: Write, I: ExactSizeIterator<Item = Vec<u8>>>(
+  width: u16,
+  height: u16,
+  delay: u16,
+  frames: I,
+  palette: &[u8],
+  trns_palette: &[u8],
+  w: T
+) -> io::Result<()> {
+  let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+  encoder.set_color(png::ColorType::Indexed);
+  encoder.set_palette(palette);
+  encoder.set_trns(trns_palette);
+  encoder.set_animated(frames.len() as u32, 0)?;
+  encoder.set_frame_delay(delay, 1000)?;
+  let mut writer = encoder.write_header()?;
+  for frame in frames {
+    writer.write_image_data(&frame)?;
+  }
+  writer.finish()?;
+  Ok(())
+}
+
+// Nearest-neighbour upscale of an indexed image by an integer factor.
+pub fn upscale(data: &[u8], width: u16, height: u16, factor: u16) -> Vec<u8> {
+  let (width, factor) = (width as usize, factor as usize);
+  let mut output = Vec::with_capacity(data.len() * factor * factor);
+  for line in data.chunks(width).take(height as usize) {
+    let scaled: Vec<u8> = line.iter()
+      .flat_map(|v| iter::repeat_n(*v, factor))
+      .collect();
+    for _ in 0..factor {
+      output.extend_from_slice(&scaled);
+    }
+  }
+  output
+}
+
+// Downsamples an indexed image by an integer factor, every output pixel taking
+// the most common colour of its block. Blocks on the right and bottom edges
+// may be partial. Returns the image along with its new width and height.
+pub fn downscale(data: &[u8], width: u16, height: u16, factor: u16) -> (Vec<u8>, u16, u16) {
+  let (w, h, f) = (width as usize, height as usize, factor as usize);
+  let (out_w, out_h) = (w.div_ceil(f), h.div_ceil(f));
+  let mut output = Vec::with_capacity(out_w * out_h);
+  let mut counts = [0u32; 256];
+  for by in 0..out_h {
+    let rows = by * f..cmp::min(h, (by + 1) * f);
+    for bx in 0..out_w {
+      let cols = bx * f..cmp::min(w, (bx + 1) * f);
+      let block = || rows.clone().flat_map(|y| data[y * w + cols.start..y * w + cols.end].iter());
+
+      let mut best = 0u8;
+      for v in block() {
+        counts[*v as usize] += 1;
+        let (count, best_count) = (counts[*v as usize], counts[best as usize]);
+        if count > best_count || (count == best_count && *v < best) {
+          best = *v;
+        }
+      }
+      output.push(best);
+      block().for_each(|v| counts[*v as usize] = 0);
+    }
+  }
+  (output, out_w as u16, out_h as u16)
+}
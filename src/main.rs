@@ -1,8 +1,13 @@
 mod commands;
+mod error;
+mod image;
 mod models;
+mod schema;
 mod store;
 
 use clap::Parser;
+use log::error;
+use std::process;
 
 use crate::commands::{run_command, SubCommand};
 
@@ -18,5 +23,8 @@ struct Args {
 fn main() {
   env_logger::init();
   let args = Args::parse();
-  run_command(args.command);
+  if let Err(e) = run_command(args.command) {
+    error!("{}", e);
+    process::exit(1);
+  }
 }
\ No newline at end of file
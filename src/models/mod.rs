@@ -1,3 +1,21 @@
+use clap::ArgEnum;
+use serde::Deserialize;
+
 pub mod record;
 
-pub type FrameData = Vec<u32>;
\ No newline at end of file
+// colour + 1 for each pixel, 0 is transparent
+pub type FrameData = Vec<u8>;
+
+// uid of the last user to place each pixel, NO_UID if nobody has
+pub type UidFrameData = Vec<u32>;
+
+pub const NO_UID: u32 = u32::MAX;
+
+// How keyframes are stored, zstd is much smaller but slower to read.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrameEncoding {
+  #[default]
+  Raw,
+  Zstd,
+}
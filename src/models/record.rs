@@ -1,36 +1,121 @@
 use std::io::{self, BufWriter, Write};
 use std::mem;
-use std::slice;
 
 pub const TILE_PLACEMENT_VERSION_ID: u16 = 0x4200;
 pub const TILE_KEYFRAME_VERSION_ID: u16 = 0x6900;
+pub const TILE_PIXEL_INDEX_VERSION_ID: u16 = 0x7700;
+pub const TILE_USER_INDEX_VERSION_ID: u16 = 0x7500;
+pub const USER_DICTIONARY_VERSION_ID: u16 = 0x4400;
 
-pub trait Record {}
+// Format versions of the tile files:
+// 1: headers without the signature, format or flags, from before versioning
+// 2: headers start with the common preamble below
+// 3: keyframes hold a u8 colour plane and optionally a u32 uid plane, rather
+//    than (uid << 8) + colour + 1 packed into a u32
+pub const FORMAT_VERSION: u16 = 3;
+pub const FORMAT_COLOUR_FRAMES: u16 = 3;
 
-#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+// Every header starts with 0: signature, 4: version (the kind of file),
+// 6: format, 8: flags, 12: reserved, followed by the fields for the kind.
+const SIGNATURE: &[u8; 4] = b"PLVW";
+
+pub const LOG_FLAG_COMPACT: u32 = 0x1;
+pub const FRAME_FLAG_ZSTD: u32 = 0x1;
+pub const FRAME_FLAG_UIDS: u32 = 0x2;
+pub const FRAME_FLAG_TABLE: u32 = 0x4;
+pub const FRAME_FLAG_TIMED: u32 = 0x8;
+
+// Records are stored little endian in the fixed layouts listed on each struct
+// as `offset: field`, with padding written as zeros.
+pub trait Record: Sized {
+  const SIZE: usize;
+
+  // Writes the record to the first SIZE bytes of buf.
+  fn encode(&self, buf: &mut [u8]);
+  // Reads the record from the first SIZE bytes of buf.
+  fn decode(buf: &[u8]) -> Self;
+}
+
+// A file header, which can also be read from files written in older formats.
+pub trait Header: Record {
+  // The kind of file, stored in the version field.
+  const VERSION: u16;
+  // Flags understood by this build, files with any others are rejected.
+  const FLAGS: u32;
+  const LEGACY_SIZE: usize;
+
+  // Reads a format 1 header from the first LEGACY_SIZE bytes of buf.
+  fn decode_legacy(buf: &[u8]) -> Self;
+  fn version(&self) -> u16;
+  fn flags(&self) -> u32;
+}
+
+// 0: ts, 4: uid, 8: x, 10: y, 12: color, 13: isblk (0 or 1), 14: padding
+//
+// repr(C) makes the in-memory layout the same as the encoding on little
+// endian machines, letting placement logs be viewed in place. isblk is kept as
+// the stored byte so that any value is valid, use is_blk to read it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Placement {
   pub ts: u32,
   pub uid: u32,
   pub x: u16,
   pub y: u16,
   pub color: u8,
-  pub isblk: bool
+  pub isblk: u8
+}
+
+impl Placement {
+  pub fn is_blk(&self) -> bool {
+    self.isblk != 0
+  }
 }
 
+// 16: start, 24: count, 28: max_uid, 32: size, 34: start_x, 36: start_y,
+// 38: padding
+//
+// format 1: 0: start, 8: count, 12: max_uid, 16: version, 18: size,
+// 20: start_x, 22: start_y
+//
+// Followed by count placements, or with LOG_FLAG_COMPACT a compact log as
+// described in store::placements.
 #[derive(Debug)]
 pub struct TilePlacementHeader {
   pub version: u16,
+  pub flags: u32,
   pub size: u16,
   pub start_x: u16,
   pub start_y: u16,
   pub start: u64,
   pub count: u32,
-  pub uid_count: u32,
+  // the largest uid in the log
+  pub max_uid: u32,
 }
 
-#[derive(Debug)]
+// 16: interval, 20: count, 24: size, 26: start_x, 28: start_y, 30: padding
+//
+// format 1: 0: interval, 4: count, 8: version, 10: size, 12: start_x,
+// 14: start_y
+//
+// Followed by count frames of size * size u8 colour indices, then with
+// FRAME_FLAG_UIDS count frames of size * size u32 uids of the last user to
+// place each pixel. With FRAME_FLAG_ZSTD, it is instead followed by a u64
+// offset from the end of the offsets for each frame plus one, where
+// offsets[i]..offsets[i + 1] is frame i compressed with zstd.
+//
+// Frames are taken every interval placements, or with FRAME_FLAG_TIMED at
+// each multiple of interval milliseconds with placements since the last. With
+// FRAME_FLAG_TABLE frames can also be taken in between, so the frames are
+// followed by count u32 pairs of the frame's timestamp relative to the tile
+// start and the index of the first placement after it.
+//
+// Before format 3, frames were (uid << 8) + colour + 1 as u32 per pixel, with
+// an unused extra frame at the end when not compressed.
+#[derive(Clone, Debug)]
 pub struct TileKeyframeHeader {
   pub version: u16,
+  pub flags: u32,
   pub size: u16,
   pub start_x: u16,
   pub start_y: u16,
@@ -38,16 +123,550 @@ pub struct TileKeyframeHeader {
   pub count: u32,
 }
 
-impl Record for Placement {}
-impl Record for TileKeyframeHeader {}
-impl Record for TilePlacementHeader {}
+// 16: count, 20: size, 22: start_x, 24: start_y, 26: padding
+//
+// format 1: 0: count, 4: version, 6: size, 8: start_x, 10: start_y
+//
+// Followed by size * size + 1 u32 offsets into the list of count u32
+// placement indices, grouped by pixel.
+#[derive(Debug)]
+pub struct TilePixelIndexHeader {
+  pub version: u16,
+  pub flags: u32,
+  pub size: u16,
+  pub start_x: u16,
+  pub start_y: u16,
+  pub count: u32,
+}
+
+// 16: count, 20: max_uid, 24: size, 26: start_x, 28: start_y, 30: padding
+//
+// format 1: 0: count, 4: max_uid, 8: version, 10: size, 12: start_x,
+// 14: start_y
+//
+// Followed by max_uid + 2 u32 offsets into the list of count u32
+// placement indices, grouped by user id.
+#[derive(Debug)]
+pub struct TileUserIndexHeader {
+  pub version: u16,
+  pub flags: u32,
+  pub size: u16,
+  pub start_x: u16,
+  pub start_y: u16,
+  pub count: u32,
+  pub max_uid: u32,
+}
+
+// 16: count, 20: padding
+//
+// Followed by count + 1 u64 offsets into the original user ids, where
+// offsets[uid]..offsets[uid + 1] is the id given uid, then count u32 uids
+// sorted by their original ids, then the original ids. Written by parse for
+// inputs with hashed user ids, there was never a format 1 dictionary.
+#[derive(Debug)]
+pub struct UserDictionaryHeader {
+  pub version: u16,
+  pub flags: u32,
+  pub count: u32,
+}
+
+const _: () = assert!(mem::size_of::<Placement>() == Placement::SIZE);
+const _: () = assert!(mem::offset_of!(Placement, isblk) == 13);
+
+fn put_u16(buf: &mut [u8], offset: usize, v: u16) {
+  buf[offset..offset + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, v: u32) {
+  buf[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], offset: usize, v: u64) {
+  buf[offset..offset + 8].copy_from_slice(&v.to_le_bytes());
+}
+
+fn get_u16(buf: &[u8], offset: usize) -> u16 {
+  u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+pub fn get_u32(buf: &[u8], offset: usize) -> u32 {
+  u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn get_u64(buf: &[u8], offset: usize) -> u64 {
+  u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn encode_preamble(buf: &mut [u8], version: u16, flags: u32) {
+  buf[0..4].copy_from_slice(SIGNATURE);
+  put_u16(buf, 4, version);
+  put_u16(buf, 6, FORMAT_VERSION);
+  put_u32(buf, 8, flags);
+  put_u32(buf, 12, 0);
+}
+
+// Reads the header at the start of buf, returning it along with its format
+// and size, or None if buf is too short to hold it.
+pub fn decode_header<H: Header>(buf: &[u8]) -> Option<(H, u16, usize)> {
+  if buf.len() >= H::SIZE && buf.starts_with(SIGNATURE) {
+    Some((H::decode(buf), get_u16(buf, 6), H::SIZE))
+  } else if buf.len() >= H::LEGACY_SIZE && !buf.starts_with(SIGNATURE) {
+    Some((H::decode_legacy(buf), 1, H::LEGACY_SIZE))
+  } else {
+    None
+  }
+}
+
+impl Record for Placement {
+  const SIZE: usize = 16;
+
+  fn encode(&self, buf: &mut [u8]) {
+    put_u32(buf, 0, self.ts);
+    put_u32(buf, 4, self.uid);
+    put_u16(buf, 8, self.x);
+    put_u16(buf, 10, self.y);
+    buf[12] = self.color;
+    buf[13] = self.isblk;
+    put_u16(buf, 14, 0);
+  }
+
+  fn decode(buf: &[u8]) -> Placement {
+    Placement {
+      ts: get_u32(buf, 0),
+      uid: get_u32(buf, 4),
+      x: get_u16(buf, 8),
+      y: get_u16(buf, 10),
+      color: buf[12],
+      isblk: buf[13],
+    }
+  }
+}
+
+impl Record for TilePlacementHeader {
+  const SIZE: usize = 40;
+
+  fn encode(&self, buf: &mut [u8]) {
+    encode_preamble(buf, self.version, self.flags);
+    put_u64(buf, 16, self.start);
+    put_u32(buf, 24, self.count);
+    put_u32(buf, 28, self.max_uid);
+    put_u16(buf, 32, self.size);
+    put_u16(buf, 34, self.start_x);
+    put_u16(buf, 36, self.start_y);
+    put_u16(buf, 38, 0);
+  }
+
+  fn decode(buf: &[u8]) -> TilePlacementHeader {
+    TilePlacementHeader {
+      version: get_u16(buf, 4),
+      flags: get_u32(buf, 8),
+      start: get_u64(buf, 16),
+      count: get_u32(buf, 24),
+      max_uid: get_u32(buf, 28),
+      size: get_u16(buf, 32),
+      start_x: get_u16(buf, 34),
+      start_y: get_u16(buf, 36),
+    }
+  }
+}
+
+impl Header for TilePlacementHeader {
+  const VERSION: u16 = TILE_PLACEMENT_VERSION_ID;
+  const FLAGS: u32 = LOG_FLAG_COMPACT;
+  const LEGACY_SIZE: usize = 24;
+
+  fn decode_legacy(buf: &[u8]) -> TilePlacementHeader {
+    TilePlacementHeader {
+      start: get_u64(buf, 0),
+      count: get_u32(buf, 8),
+      max_uid: get_u32(buf, 12),
+      version: get_u16(buf, 16),
+      flags: 0,
+      size: get_u16(buf, 18),
+      start_x: get_u16(buf, 20),
+      start_y: get_u16(buf, 22),
+    }
+  }
+
+  fn version(&self) -> u16 {
+    self.version
+  }
+
+  fn flags(&self) -> u32 {
+    self.flags
+  }
+}
+
+impl Record for TileKeyframeHeader {
+  const SIZE: usize = 32;
+
+  fn encode(&self, buf: &mut [u8]) {
+    encode_preamble(buf, self.version, self.flags);
+    put_u32(buf, 16, self.interval);
+    put_u32(buf, 20, self.count);
+    put_u16(buf, 24, self.size);
+    put_u16(buf, 26, self.start_x);
+    put_u16(buf, 28, self.start_y);
+    put_u16(buf, 30, 0);
+  }
+
+  fn decode(buf: &[u8]) -> TileKeyframeHeader {
+    TileKeyframeHeader {
+      version: get_u16(buf, 4),
+      flags: get_u32(buf, 8),
+      interval: get_u32(buf, 16),
+      count: get_u32(buf, 20),
+      size: get_u16(buf, 24),
+      start_x: get_u16(buf, 26),
+      start_y: get_u16(buf, 28),
+    }
+  }
+}
+
+impl Header for TileKeyframeHeader {
+  const VERSION: u16 = TILE_KEYFRAME_VERSION_ID;
+  const FLAGS: u32 = FRAME_FLAG_ZSTD | FRAME_FLAG_UIDS | FRAME_FLAG_TABLE | FRAME_FLAG_TIMED;
+  const LEGACY_SIZE: usize = 16;
+
+  fn decode_legacy(buf: &[u8]) -> TileKeyframeHeader {
+    TileKeyframeHeader {
+      interval: get_u32(buf, 0),
+      count: get_u32(buf, 4),
+      version: get_u16(buf, 8),
+      flags: 0,
+      size: get_u16(buf, 10),
+      start_x: get_u16(buf, 12),
+      start_y: get_u16(buf, 14),
+    }
+  }
+
+  fn version(&self) -> u16 {
+    self.version
+  }
+
+  fn flags(&self) -> u32 {
+    self.flags
+  }
+}
+
+impl Record for TilePixelIndexHeader {
+  const SIZE: usize = 28;
+
+  fn encode(&self, buf: &mut [u8]) {
+    encode_preamble(buf, self.version, self.flags);
+    put_u32(buf, 16, self.count);
+    put_u16(buf, 20, self.size);
+    put_u16(buf, 22, self.start_x);
+    put_u16(buf, 24, self.start_y);
+    put_u16(buf, 26, 0);
+  }
+
+  fn decode(buf: &[u8]) -> TilePixelIndexHeader {
+    TilePixelIndexHeader {
+      version: get_u16(buf, 4),
+      flags: get_u32(buf, 8),
+      count: get_u32(buf, 16),
+      size: get_u16(buf, 20),
+      start_x: get_u16(buf, 22),
+      start_y: get_u16(buf, 24),
+    }
+  }
+}
+
+impl Header for TilePixelIndexHeader {
+  const VERSION: u16 = TILE_PIXEL_INDEX_VERSION_ID;
+  const FLAGS: u32 = 0;
+  const LEGACY_SIZE: usize = 12;
+
+  fn decode_legacy(buf: &[u8]) -> TilePixelIndexHeader {
+    TilePixelIndexHeader {
+      count: get_u32(buf, 0),
+      version: get_u16(buf, 4),
+      flags: 0,
+      size: get_u16(buf, 6),
+      start_x: get_u16(buf, 8),
+      start_y: get_u16(buf, 10),
+    }
+  }
+
+  fn version(&self) -> u16 {
+    self.version
+  }
+
+  fn flags(&self) -> u32 {
+    self.flags
+  }
+}
+
+impl Record for TileUserIndexHeader {
+  const SIZE: usize = 32;
+
+  fn encode(&self, buf: &mut [u8]) {
+    encode_preamble(buf, self.version, self.flags);
+    put_u32(buf, 16, self.count);
+    put_u32(buf, 20, self.max_uid);
+    put_u16(buf, 24, self.size);
+    put_u16(buf, 26, self.start_x);
+    put_u16(buf, 28, self.start_y);
+    put_u16(buf, 30, 0);
+  }
+
+  fn decode(buf: &[u8]) -> TileUserIndexHeader {
+    TileUserIndexHeader {
+      version: get_u16(buf, 4),
+      flags: get_u32(buf, 8),
+      count: get_u32(buf, 16),
+      max_uid: get_u32(buf, 20),
+      size: get_u16(buf, 24),
+      start_x: get_u16(buf, 26),
+      start_y: get_u16(buf, 28),
+    }
+  }
+}
+
+impl Header for TileUserIndexHeader {
+  const VERSION: u16 = TILE_USER_INDEX_VERSION_ID;
+  const FLAGS: u32 = 0;
+  const LEGACY_SIZE: usize = 16;
+
+  fn decode_legacy(buf: &[u8]) -> TileUserIndexHeader {
+    TileUserIndexHeader {
+      count: get_u32(buf, 0),
+      max_uid: get_u32(buf, 4),
+      version: get_u16(buf, 8),
+      flags: 0,
+      size: get_u16(buf, 10),
+      start_x: get_u16(buf, 12),
+      start_y: get_u16(buf, 14),
+    }
+  }
+
+  fn version(&self) -> u16 {
+    self.version
+  }
+
+  fn flags(&self) -> u32 {
+    self.flags
+  }
+}
 
 pub fn write_record<S: Record, T: Write>(data: &S, writer: &mut BufWriter<T>) -> io::Result<usize> {
-  unsafe {
-    let buffer = slice::from_raw_parts(
-      data as *const S as *const u8,
-      mem::size_of::<S>()
+  // large enough for any record, avoiding an allocation per placement
+  let mut buffer = [0u8; 64];
+  data.encode(&mut buffer[..S::SIZE]);
+  writer.write_all(&buffer[..S::SIZE])?;
+  Ok(S::SIZE)
+}
+
+// Views an encoded placement log in place, or returns None when the bytes
+// can't be an in-memory Placement array, such as on big endian machines.
+// Every byte pattern is a valid Placement, so the contents aren't read.
+pub fn view_placements(data: &[u8]) -> Option<&[Placement]> {
+  if cfg!(target_endian = "big") || !data.len().is_multiple_of(Placement::SIZE) ||
+    !(data.as_ptr() as usize).is_multiple_of(mem::align_of::<Placement>()) {
+    return None;
+  }
+  Some(unsafe {
+    std::slice::from_raw_parts(data.as_ptr() as *const Placement, data.len() / Placement::SIZE)
+  })
+}
+
+impl Record for UserDictionaryHeader {
+  const SIZE: usize = 24;
+
+  fn encode(&self, buf: &mut [u8]) {
+    encode_preamble(buf, self.version, self.flags);
+    put_u32(buf, 16, self.count);
+    put_u32(buf, 20, 0);
+  }
+
+  fn decode(buf: &[u8]) -> UserDictionaryHeader {
+    UserDictionaryHeader {
+      version: get_u16(buf, 4),
+      flags: get_u32(buf, 8),
+      count: get_u32(buf, 16),
+    }
+  }
+}
+
+impl Header for UserDictionaryHeader {
+  const VERSION: u16 = USER_DICTIONARY_VERSION_ID;
+  const FLAGS: u32 = 0;
+  const LEGACY_SIZE: usize = UserDictionaryHeader::SIZE;
+
+  // fails the version check, as there are no format 1 dictionaries
+  fn decode_legacy(_buf: &[u8]) -> UserDictionaryHeader {
+    UserDictionaryHeader { version: 0, flags: 0, count: 0 }
+  }
+
+  fn version(&self) -> u16 {
+    self.version
+  }
+
+  fn flags(&self) -> u32 {
+    self.flags
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Encodes the record, checks it against the documented bytes, and that it
+  // decodes to the same bytes again.
+  fn check_encoding<R: Record>(record: &R, expected: &[u8]) -> R {
+    let mut buf = vec![0xaa; R::SIZE];
+    record.encode(&mut buf);
+    assert_eq!(buf, expected);
+    let decoded = R::decode(&buf);
+    let mut again = vec![0xaa; R::SIZE];
+    decoded.encode(&mut again);
+    assert_eq!(again, expected);
+    decoded
+  }
+
+  fn preamble(version: u16, flags: u32) -> Vec<u8> {
+    let mut bytes = b"PLVW".to_vec();
+    bytes.extend(version.to_le_bytes());
+    bytes.extend(FORMAT_VERSION.to_le_bytes());
+    bytes.extend(flags.to_le_bytes());
+    bytes.extend([0; 4]);
+    bytes
+  }
+
+  #[test]
+  fn placement_layout() {
+    let placement = Placement { ts: 0x04030201, uid: 0x08070605, x: 0x0a09, y: 0x0c0b, color: 0x0d, isblk: 1 };
+    let decoded = check_encoding(&placement, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 1, 0, 0]);
+    assert_eq!(decoded, placement);
+  }
+
+  #[test]
+  fn placement_header_layout() {
+    let header = TilePlacementHeader {
+      version: TILE_PLACEMENT_VERSION_ID,
+      flags: LOG_FLAG_COMPACT,
+      size: 0x0201,
+      start_x: 0x0403,
+      start_y: 0x0605,
+      start: 0x1817161514131211,
+      count: 0x24232221,
+      max_uid: 0x34333231,
+    };
+    let mut expected = preamble(TILE_PLACEMENT_VERSION_ID, LOG_FLAG_COMPACT);
+    expected.extend([0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18]);
+    expected.extend([0x21, 0x22, 0x23, 0x24, 0x31, 0x32, 0x33, 0x34]);
+    expected.extend([1, 2, 3, 4, 5, 6, 0, 0]);
+    let decoded = check_encoding(&header, &expected);
+    assert_eq!(
+      (decoded.version, decoded.flags, decoded.start, decoded.count, decoded.max_uid),
+      (header.version, header.flags, header.start, header.count, header.max_uid)
     );
-    writer.write(buffer)
+    assert_eq!((decoded.size, decoded.start_x, decoded.start_y), (header.size, header.start_x, header.start_y));
+  }
+
+  #[test]
+  fn keyframe_header_layout() {
+    let flags = FRAME_FLAG_ZSTD | FRAME_FLAG_UIDS | FRAME_FLAG_TABLE | FRAME_FLAG_TIMED;
+    let header = TileKeyframeHeader {
+      version: TILE_KEYFRAME_VERSION_ID,
+      flags,
+      size: 0x0201,
+      start_x: 0x0403,
+      start_y: 0x0605,
+      interval: 0x14131211,
+      count: 0x24232221,
+    };
+    let mut expected = preamble(TILE_KEYFRAME_VERSION_ID, flags);
+    expected.extend([0x11, 0x12, 0x13, 0x14, 0x21, 0x22, 0x23, 0x24]);
+    expected.extend([1, 2, 3, 4, 5, 6, 0, 0]);
+    let decoded = check_encoding(&header, &expected);
+    assert_eq!((decoded.interval, decoded.count, decoded.flags), (header.interval, header.count, flags));
+  }
+
+  #[test]
+  fn pixel_index_header_layout() {
+    let header = TilePixelIndexHeader {
+      version: TILE_PIXEL_INDEX_VERSION_ID,
+      flags: 0,
+      size: 0x0201,
+      start_x: 0x0403,
+      start_y: 0x0605,
+      count: 0x14131211,
+    };
+    let mut expected = preamble(TILE_PIXEL_INDEX_VERSION_ID, 0);
+    expected.extend([0x11, 0x12, 0x13, 0x14, 1, 2, 3, 4, 5, 6, 0, 0]);
+    let decoded = check_encoding(&header, &expected);
+    assert_eq!((decoded.count, decoded.size), (header.count, header.size));
+  }
+
+  #[test]
+  fn user_index_header_layout() {
+    let header = TileUserIndexHeader {
+      version: TILE_USER_INDEX_VERSION_ID,
+      flags: 0,
+      size: 0x0201,
+      start_x: 0x0403,
+      start_y: 0x0605,
+      count: 0x14131211,
+      max_uid: 0x24232221,
+    };
+    let mut expected = preamble(TILE_USER_INDEX_VERSION_ID, 0);
+    expected.extend([0x11, 0x12, 0x13, 0x14, 0x21, 0x22, 0x23, 0x24, 1, 2, 3, 4, 5, 6, 0, 0]);
+    let decoded = check_encoding(&header, &expected);
+    assert_eq!((decoded.count, decoded.max_uid), (header.count, header.max_uid));
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn user_dictionary_header_layout() {
+    let header = UserDictionaryHeader { version: USER_DICTIONARY_VERSION_ID, flags: 0, count: 0x14131211 };
+    let mut expected = preamble(USER_DICTIONARY_VERSION_ID, 0);
+    expected.extend([0x11, 0x12, 0x13, 0x14, 0, 0, 0, 0]);
+    assert_eq!(check_encoding(&header, &expected).count, header.count);
+  }
+
+  #[test]
+  fn legacy_headers() {
+    let mut legacy = vec![0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18];
+    legacy.extend([0x21, 0x22, 0x23, 0x24, 0x31, 0x32, 0x33, 0x34]);
+    legacy.extend(TILE_PLACEMENT_VERSION_ID.to_le_bytes());
+    legacy.extend([1, 2, 3, 4, 5, 6]);
+    let (header, format, size) = decode_header::<TilePlacementHeader>(&legacy).unwrap();
+    assert_eq!((format, size), (1, TilePlacementHeader::LEGACY_SIZE));
+    assert_eq!((header.start, header.count, header.max_uid), (0x1817161514131211, 0x24232221, 0x34333231));
+    assert_eq!(header.version, TILE_PLACEMENT_VERSION_ID);
+    assert_eq!((header.size, header.start_x, header.start_y), (0x0201, 0x0403, 0x0605));
+
+    let mut legacy = vec![0x11, 0x12, 0x13, 0x14, 0x21, 0x22, 0x23, 0x24];
+    legacy.extend(TILE_KEYFRAME_VERSION_ID.to_le_bytes());
+    legacy.extend([1, 2, 3, 4, 5, 6]);
+    let (header, format, _) = decode_header::<TileKeyframeHeader>(&legacy).unwrap();
+    assert_eq!((format, header.interval, header.count, header.size), (1, 0x14131211, 0x24232221, 0x0201));
+
+    assert!(decode_header::<TilePlacementHeader>(b"PLVW").is_none());
+  }
+
+  #[test]
+  fn placements_view_in_place() {
+    let placements = [
+      Placement { ts: 1, uid: 2, x: 3, y: 4, color: 5, isblk: 0 },
+      Placement { ts: 6, uid: 7, x: 8, y: 9, color: 10, isblk: 1 },
+    ];
+    // u64s keep the buffer aligned for Placement
+    let mut words = [0u64; 4];
+    let bytes = unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, 32) };
+    for (p, buf) in placements.iter().zip(bytes.chunks_exact_mut(Placement::SIZE)) {
+      p.encode(buf);
+    }
+    if cfg!(target_endian = "little") {
+      assert_eq!(view_placements(bytes), Some(&placements[..]));
+    }
+    assert_eq!(view_placements(&bytes[1..17]), None);
+    // any isblk byte can be viewed, and reads as blk when non-zero
+    bytes[Placement::SIZE + 13] = 2;
+    if cfg!(target_endian = "little") {
+      assert!(view_placements(bytes).unwrap()[1].is_blk());
+    }
+    assert!(Placement::decode(&bytes[Placement::SIZE..]).is_blk());
+  }
+}
@@ -0,0 +1,614 @@
+use clap::ArgEnum;
+use csv::ByteRecord;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::{cmp, str};
+
+use crate::error::{Error, Result};
+
+// Built in layouts of the CSV given to parse.
+#[derive(ArgEnum, Clone, Copy, PartialEq)]
+pub enum InputSchema {
+  // Integer user ids and coordinates in their own columns, as the logs have
+  // to be cleaned up to
+  Plain,
+  // The official 2022 dump, with hashed user ids, hex colours and the
+  // coordinates in one column
+  #[clap(name = "reddit-2022")]
+  Reddit2022,
+}
+
+impl InputSchema {
+  // The schema for an input with the given header row. The plain layout only
+  // reads rectangles from inputs with both corner columns.
+  pub fn schema(&self, headers: &ByteRecord) -> Schema {
+    let column = |name: &str| Some(String::from(name));
+    let corners = ["x2_coordinate", "y2_coordinate"].iter()
+      .all(|name| headers.iter().any(|h| h == name.as_bytes()));
+    match self {
+      InputSchema::Plain => Schema {
+        timestamp: String::from("ts"),
+        timestamp_format: TimestampFormat::EpochMs,
+        user: String::from("user_id"),
+        user_format: UserFormat::Id,
+        color: String::from("color"),
+        color_format: ColorFormat::Index,
+        coordinate: None,
+        x: column("x_coordinate"),
+        y: column("y_coordinate"),
+        x2: column("x2_coordinate").filter(|_| corners),
+        y2: column("y2_coordinate").filter(|_| corners),
+      },
+      InputSchema::Reddit2022 => Schema {
+        timestamp: String::from("timestamp"),
+        timestamp_format: TimestampFormat::Iso8601,
+        user: String::from("user_id"),
+        user_format: UserFormat::Hash,
+        color: String::from("pixel_color"),
+        color_format: ColorFormat::Hex,
+        coordinate: column("coordinate"),
+        x: None,
+        y: None,
+        x2: None,
+        y2: None,
+      },
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampFormat {
+  // unix timestamp in milliseconds
+  #[default]
+  EpochMs,
+  // unix timestamp in seconds, optionally with a fraction
+  EpochS,
+  // UTC time like 2022-04-04 00:53:51.577 UTC
+  #[serde(rename = "iso-8601")]
+  Iso8601,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UserFormat {
+  // integer id
+  #[default]
+  Id,
+  // opaque string, given an integer id the first time it's seen
+  Hash,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorFormat {
+  // index into the palette
+  #[default]
+  Index,
+  // colour like #FF4500, looked up in the palette
+  Hex,
+}
+
+// Names and formats of the columns of an input, given in a dataset's config
+// or by a built in schema. Positions are either one combined coordinate
+// column of "x,y", "x1,y1,x2,y2" for a rectangle or "{X: x, Y: y, R: r}" for
+// a circle, or separate columns with x2 and y2 for rectangles optional. Every
+// column named must be in the input.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+  pub timestamp: String,
+  #[serde(default)]
+  pub timestamp_format: TimestampFormat,
+  pub user: String,
+  #[serde(default)]
+  pub user_format: UserFormat,
+  pub color: String,
+  #[serde(default)]
+  pub color_format: ColorFormat,
+  pub coordinate: Option<String>,
+  pub x: Option<String>,
+  pub y: Option<String>,
+  pub x2: Option<String>,
+  pub y2: Option<String>,
+}
+
+// Who made a placement, as given in the input.
+pub enum InputUser<'a> {
+  Id(u32),
+  // opaque string that needs interning
+  Hash(&'a [u8]),
+}
+
+// Pixels covered by a record, from its x, y.
+pub enum InputShape {
+  Pixel,
+  // to x2, y2 inclusive
+  Rectangle(i32, i32),
+  // centred on x, y with a radius
+  Circle(u16),
+}
+
+// The rectangle of input coordinates [x0, x1) x [y0, y1) covered by the
+// canvas.
+#[derive(Clone, Copy)]
+pub struct CanvasBounds {
+  pub x0: i64,
+  pub y0: i64,
+  pub x1: i64,
+  pub y1: i64,
+}
+
+pub struct InputRecord<'a> {
+  pub ts: u64,
+  pub user: InputUser<'a>,
+  pub x: i32,
+  pub y: i32,
+  pub shape: InputShape,
+  pub color: u8,
+}
+
+impl InputRecord<'_> {
+  // Every pixel the record covers, with whether it's part of a larger shape.
+  // Shapes are clipped to the canvas.
+  pub fn pixels(&self, canvas: CanvasBounds) -> Vec<(i64, i64, bool)> {
+    let (x, y) = (self.x as i64, self.y as i64);
+    match self.shape {
+      InputShape::Pixel => vec![(x, y, false)],
+      InputShape::Rectangle(x2, y2) => {
+        let (x0, x1) = (cmp::max(x, canvas.x0), cmp::min(x2 as i64 + 1, canvas.x1));
+        let (y0, y1) = (cmp::max(y, canvas.y0), cmp::min(y2 as i64 + 1, canvas.y1));
+        (y0..y1).flat_map(|py| (x0..x1).map(move |px| (px, py, true))).collect()
+      },
+      InputShape::Circle(r) => {
+        let r = r as i64;
+        let (x0, x1) = (cmp::max(x - r, canvas.x0), cmp::min(x + r + 1, canvas.x1));
+        let (y0, y1) = (cmp::max(y - r, canvas.y0), cmp::min(y + r + 1, canvas.y1));
+        (y0..y1).flat_map(|py| (x0..x1).map(move |px| (px, py)))
+          .filter(|(px, py)| (px - x) * (px - x) + (py - y) * (py - y) <= r * r)
+          .map(|(px, py)| (px, py, true))
+          .collect()
+      },
+    }
+  }
+}
+
+// Where the position of a record is read from.
+enum Position {
+  Combined(usize),
+  Split { x: usize, y: usize, corner: Option<(usize, usize)> },
+}
+
+// Decodes rows of an input in some schema.
+pub struct RecordReader {
+  timestamp: (usize, TimestampFormat),
+  user: (usize, UserFormat),
+  color: usize,
+  // palette index of each 0xRRGGBB colour when colours are hex
+  colors: Option<HashMap<u32, u8>>,
+  position: Position,
+  // largest circle radius read, larger ones are malformed
+  max_radius: u16,
+}
+
+impl RecordReader {
+  // Finds the schema's columns in the input's headers. Hex colours are looked
+  // up in the palette, and circles with a radius over max_radius, the larger
+  // side of the canvas, are rejected.
+  pub fn new(
+    schema: &Schema, headers: &ByteRecord, palette: Option<&[u32]>, max_radius: u16
+  ) -> Result<RecordReader> {
+    let column = |name: &str| headers.iter().position(|h| h == name.as_bytes())
+      .ok_or_else(|| Error::InvalidArgument(format!("the input has no {} column", name)));
+
+    let colors = match schema.color_format {
+      ColorFormat::Index => None,
+      ColorFormat::Hex => {
+        let palette = palette.ok_or_else(|| Error::InvalidArgument(
+          String::from("a dataset palette is needed to read hex colours")
+        ))?;
+        Some(palette.iter().enumerate().map(|(i, c)| (*c, i as u8)).collect())
+      },
+    };
+    let position = match (&schema.coordinate, &schema.x, &schema.y) {
+      (Some(coordinate), None, None) => Position::Combined(column(coordinate)?),
+      (None, Some(x), Some(y)) => Position::Split {
+        x: column(x)?,
+        y: column(y)?,
+        // rectangles are optional, but need both corner columns
+        corner: match (&schema.x2, &schema.y2) {
+          (Some(x2), Some(y2)) => Some((column(x2)?, column(y2)?)),
+          (None, None) => None,
+          _ => return Err(Error::InvalidArgument(String::from(
+            "a schema needs both x2 and y2 columns for rectangles, or neither"
+          ))),
+        },
+      },
+      _ => return Err(Error::InvalidArgument(String::from(
+        "a schema needs either a coordinate column or x and y columns"
+      ))),
+    };
+
+    Ok(RecordReader {
+      timestamp: (column(&schema.timestamp)?, schema.timestamp_format),
+      user: (column(&schema.user)?, schema.user_format),
+      color: column(&schema.color)?,
+      colors,
+      position,
+      max_radius,
+    })
+  }
+
+  // Whether user ids need interning to integers.
+  pub fn hashed_users(&self) -> bool {
+    self.user.1 == UserFormat::Hash
+  }
+
+  pub fn read<'r>(&self, row: &'r ByteRecord) -> std::result::Result<InputRecord<'r>, String> {
+    let field = |i: usize| str::from_utf8(&row[i]).map_err(|_| String::from("field is not valid UTF-8"));
+    let number = |i: usize| -> std::result::Result<u64, String> {
+      let value = field(i)?;
+      value.parse().map_err(|e| format!("invalid number {}: {}", value, e))
+    };
+    let position = |i: usize| -> std::result::Result<i32, String> {
+      let value = field(i)?;
+      value.parse().map_err(|e| format!("invalid number {}: {}", value, e))
+    };
+
+    let (ts, ts_format) = self.timestamp;
+    let ts = match ts_format {
+      TimestampFormat::EpochMs => field(ts)?.parse().ok(),
+      TimestampFormat::EpochS => parse_epoch_seconds(field(ts)?),
+      TimestampFormat::Iso8601 => parse_iso_timestamp(field(ts)?),
+    }.ok_or_else(|| format!("invalid timestamp {}", field(ts).unwrap_or_default()))?;
+
+    let user = match self.user {
+      (i, UserFormat::Id) => InputUser::Id(narrow(number(i)?, field(i)?)?),
+      (i, UserFormat::Hash) => InputUser::Hash(&row[i]),
+    };
+
+    let color = match &self.colors {
+      None => narrow(number(self.color)?, field(self.color)?)?,
+      Some(colors) => {
+        let hex = field(self.color)?;
+        *parse_hex_colour(hex).and_then(|c| colors.get(&c))
+          .ok_or_else(|| format!("colour {} is not in the palette", hex))?
+      },
+    };
+
+    let (x, y, shape) = match self.position {
+      Position::Combined(i) => {
+        let coordinate = field(i)?;
+        parse_coordinate(coordinate).ok_or_else(|| format!("invalid coordinate {}", coordinate))?
+      },
+      Position::Split { x, y, corner } => {
+        let (x, y) = (position(x)?, position(y)?);
+        let shape = match corner {
+          Some((x2, y2)) if !row[x2].is_empty() && !row[y2].is_empty() => {
+            InputShape::Rectangle(position(x2)?, position(y2)?)
+          },
+          _ => InputShape::Pixel,
+        };
+        (x, y, shape)
+      },
+    };
+    if let InputShape::Circle(r) = shape {
+      if r > self.max_radius {
+        return Err(format!("circle radius {} is larger than the canvas", r));
+      }
+    }
+
+    Ok(InputRecord { ts, user, x, y, shape, color })
+  }
+}
+
+// Converts a number read from the field to a smaller type.
+fn narrow<T: TryFrom<u64>>(value: u64, field: &str) -> std::result::Result<T, String> {
+  T::try_from(value).map_err(|_| format!("{} is out of range", field))
+}
+
+// Position and shape of a combined coordinate, see Schema.
+pub fn parse_coordinate(s: &str) -> Option<(i32, i32, InputShape)> {
+  let s = s.trim();
+  if let Some(circle) = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+    let mut values = HashMap::new();
+    for part in circle.split(',') {
+      let (key, value) = part.split_once(':')?;
+      values.insert(key.trim(), value.trim().parse::<i32>().ok()?);
+    }
+    let radius = u16::try_from(*values.get("R")?).ok()?;
+    return Some((*values.get("X")?, *values.get("Y")?, InputShape::Circle(radius)));
+  }
+  let values = s.split(',').map(|v| v.trim().parse::<i32>().ok()).collect::<Option<Vec<_>>>()?;
+  match values[..] {
+    [x, y] => Some((x, y, InputShape::Pixel)),
+    [x, y, x2, y2] => Some((x, y, InputShape::Rectangle(x2, y2))),
+    _ => None,
+  }
+}
+
+// 0xRRGGBB value of a colour like #FF4500.
+pub fn parse_hex_colour(s: &str) -> Option<u32> {
+  let hex = s.trim().strip_prefix('#')?;
+  if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+    return None
+  }
+  u32::from_str_radix(hex, 16).ok()
+}
+
+// Unix timestamp in milliseconds of a number of seconds like 1649112460.186.
+pub fn parse_epoch_seconds(s: &str) -> Option<u64> {
+  let (seconds, fraction) = s.trim().split_once('.').unwrap_or((s.trim(), ""));
+  if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+    return None
+  }
+  Some(seconds.parse::<u64>().ok()?.checked_mul(1000)? + parse_millis(fraction))
+}
+
+// Milliseconds of the digits after a decimal point, dropping any finer ones.
+fn parse_millis(fraction: &str) -> u64 {
+  fraction.bytes().chain([b'0'; 3]).take(3).fold(0, |ms, b| ms * 10 + (b - b'0') as u64)
+}
+
+// Unix timestamp in milliseconds of a UTC time like 2022-04-04 00:53:51.577 UTC
+// or 2022-04-04T00:53:51Z.
+pub fn parse_iso_timestamp(s: &str) -> Option<u64> {
+  let s = s.trim();
+  let s = ["UTC", "Z", "+00:00", "+0000"].iter()
+    .find_map(|suffix| s.strip_suffix(suffix))
+    .unwrap_or(s)
+    .trim_end();
+  let (date, time) = s.split_once([' ', 'T'])?;
+
+  let mut date = date.splitn(3, '-').map(|v| v.parse::<u32>().ok());
+  let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+  if !(1970..10000).contains(&year) || !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+    return None
+  }
+
+  let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+  let mut time = time.splitn(3, ':').map(|v| v.parse::<u32>().ok());
+  let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+  if hour > 23 || minute > 59 || second > 60 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+    return None
+  }
+
+  let days = days_from_epoch(year, month, day);
+  Some(((days * 24 + hour as u64) * 60 + minute as u64) * 60_000 + second as u64 * 1000 + parse_millis(fraction))
+}
+
+fn is_leap_year(year: u32) -> bool {
+  year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+  match month {
+    2 if is_leap_year(year) => 29,
+    2 => 28,
+    4 | 6 | 9 | 11 => 30,
+    _ => 31,
+  }
+}
+
+// Days from 1970-01-01 to the date.
+fn days_from_epoch(year: u32, month: u32, day: u32) -> u64 {
+  let years = (1970..year).map(|y| if is_leap_year(y) { 366 } else { 365 }).sum::<u64>();
+  let months = (1..month).map(|m| days_in_month(year, m) as u64).sum::<u64>();
+  years + months + day as u64 - 1
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn reader(schema: InputSchema, headers: &[&str], palette: Option<&[u32]>) -> RecordReader {
+    let headers = ByteRecord::from(headers.to_vec());
+    RecordReader::new(&schema.schema(&headers), &headers, palette, 1000).unwrap()
+  }
+
+  #[test]
+  fn iso_timestamps() {
+    assert_eq!(parse_iso_timestamp("2022-04-04 00:53:51.577 UTC"), Some(1649033631577));
+    assert_eq!(parse_iso_timestamp("2022-04-04 00:53:51 UTC"), Some(1649033631000));
+    assert_eq!(parse_iso_timestamp("2022-04-04T00:53:51Z"), Some(1649033631000));
+    assert_eq!(parse_iso_timestamp("2022-04-04 00:53:51+00:00"), Some(1649033631000));
+    assert_eq!(parse_iso_timestamp(" 2022-04-04 00:53:51.5UTC "), Some(1649033631500));
+    assert_eq!(parse_iso_timestamp("1970-01-01 00:00:00 UTC"), Some(0));
+    assert_eq!(parse_iso_timestamp("2020-02-29 12:00:00"), Some(1582977600000));
+  }
+
+  #[test]
+  fn iso_fractional_seconds() {
+    assert_eq!(parse_iso_timestamp("2022-04-04 00:53:51.1 UTC"), Some(1649033631100));
+    assert_eq!(parse_iso_timestamp("2022-04-04 00:53:51.05 UTC"), Some(1649033631050));
+    // digits past milliseconds are dropped rather than rounded
+    assert_eq!(parse_iso_timestamp("2022-04-04 00:53:51.123999 UTC"), Some(1649033631123));
+    assert_eq!(parse_iso_timestamp("2022-04-04 00:53:51. UTC"), Some(1649033631000));
+  }
+
+  #[test]
+  fn malformed_iso_timestamps() {
+    for s in [
+      "",
+      "UTC",
+      "2022-04-04",
+      "2022-04-04 00:53",
+      "2022-04-04 00:53:51.5x UTC",
+      "2022-04-04 24:00:00 UTC",
+      "2022-04-04 00:60:00 UTC",
+      "2022-13-01 00:00:00 UTC",
+      "2022-00-01 00:00:00 UTC",
+      "2022-02-29 00:00:00 UTC",
+      "2022-04-31 00:00:00 UTC",
+      "1969-12-31 23:59:59 UTC",
+      "2022/04/04 00:53:51 UTC",
+      "2022-04-04 00:53:51 PST",
+    ] {
+      assert_eq!(parse_iso_timestamp(s), None, "{:?}", s);
+    }
+  }
+
+  #[test]
+  fn epoch_seconds() {
+    assert_eq!(parse_epoch_seconds("1649112460"), Some(1649112460000));
+    assert_eq!(parse_epoch_seconds("1649112460.186"), Some(1649112460186));
+    assert_eq!(parse_epoch_seconds("1649112460.18"), Some(1649112460180));
+    assert_eq!(parse_epoch_seconds("1649112460.18699"), Some(1649112460186));
+    assert_eq!(parse_epoch_seconds(" 0.5 "), Some(500));
+    for s in ["", ".5", "-1", "1e9", "12.3a", "12.-3", "18446744073709551615"] {
+      assert_eq!(parse_epoch_seconds(s), None, "{:?}", s);
+    }
+  }
+
+  #[test]
+  fn timestamp_formats() {
+    let headers = ["ts", "user_id", "x_coordinate", "y_coordinate", "color"];
+    let mut schema = InputSchema::Plain.schema(&ByteRecord::from(headers.to_vec()));
+    let read = |schema: &Schema, ts: &str| {
+      let reader = RecordReader::new(schema, &ByteRecord::from(headers.to_vec()), None, 1000).unwrap();
+      reader.read(&ByteRecord::from(vec![ts, "1", "2", "3", "4"])).map(|r| r.ts)
+    };
+
+    assert_eq!(read(&schema, "1649112460186"), Ok(1649112460186));
+    assert!(read(&schema, "1649112460.186").is_err());
+    assert!(read(&schema, "-1").is_err());
+
+    schema.timestamp_format = TimestampFormat::EpochS;
+    assert_eq!(read(&schema, "1649112460.186"), Ok(1649112460186));
+    assert_eq!(read(&schema, "1649112460"), Ok(1649112460000));
+    assert!(read(&schema, "x").is_err());
+
+    schema.timestamp_format = TimestampFormat::Iso8601;
+    assert_eq!(read(&schema, "2022-04-04 00:53:51.577 UTC"), Ok(1649033631577));
+    assert!(read(&schema, "1649112460186").is_err());
+  }
+
+  #[test]
+  fn hex_colours() {
+    assert_eq!(parse_hex_colour("#FF4500"), Some(0xff4500));
+    assert_eq!(parse_hex_colour("#ff4500"), Some(0xff4500));
+    assert_eq!(parse_hex_colour(" #000000 "), Some(0));
+    for s in ["", "#", "FF4500", "#FF450", "#FF45000", "#GG4500", "#+F4500"] {
+      assert_eq!(parse_hex_colour(s), None, "{:?}", s);
+    }
+  }
+
+  #[test]
+  fn hex_colours_from_palette() {
+    let palette = [0xffffff, 0xff4500, 0x000000];
+    let headers = ["timestamp", "user_id", "pixel_color", "coordinate"];
+    let reader = reader(InputSchema::Reddit2022, &headers, Some(&palette));
+    let read = |colour: &str| reader.read(&ByteRecord::from(vec![
+      "2022-04-04 00:53:51.577 UTC", "abc", colour, "1,2"
+    ])).map(|r| r.color);
+
+    assert_eq!(read("#FF4500"), Ok(1));
+    assert_eq!(read("#000000"), Ok(2));
+    assert_eq!(read("#123456"), Err(String::from("colour #123456 is not in the palette")));
+    assert!(read("FF4500").is_err());
+
+    let headers = ByteRecord::from(headers.to_vec());
+    assert!(RecordReader::new(&InputSchema::Reddit2022.schema(&headers), &headers, None, 1000).is_err());
+  }
+
+  #[test]
+  fn coordinates() {
+    assert!(matches!(parse_coordinate("12,34"), Some((12, 34, InputShape::Pixel))));
+    assert!(matches!(parse_coordinate(" 12 , 34 "), Some((12, 34, InputShape::Pixel))));
+    assert!(matches!(parse_coordinate("-12,-34"), Some((-12, -34, InputShape::Pixel))));
+    assert!(matches!(parse_coordinate("1,2,3,4"), Some((1, 2, InputShape::Rectangle(3, 4)))));
+    assert!(matches!(parse_coordinate("{X: 5, Y: -6, R: 7}"), Some((5, -6, InputShape::Circle(7)))));
+    assert!(matches!(parse_coordinate("{R: 7, X: 5, Y: 6}"), Some((5, 6, InputShape::Circle(7)))));
+    for s in [
+      "", "12", "1,2,3", "1,2,3,4,5", "1,,2", "a,b", "1.5,2", "2147483648,0",
+      "{X: 5, Y: 6}", "{X: 5, Y: 6, R: -1}", "{X: 5, Y: 6, R: 65536}", "{X 5, Y: 6, R: 7}", "{X: 5, Y: 6, R: 7",
+    ] {
+      assert!(parse_coordinate(s).is_none(), "{:?}", s);
+    }
+  }
+
+  fn record(x: i32, y: i32, shape: InputShape) -> InputRecord<'static> {
+    InputRecord { ts: 0, user: InputUser::Id(0), x, y, shape, color: 0 }
+  }
+
+  const CANVAS: CanvasBounds = CanvasBounds { x0: -10, y0: -10, x1: 10, y1: 10 };
+
+  #[test]
+  fn circles() {
+    assert_eq!(record(1, 2, InputShape::Circle(0)).pixels(CANVAS), [(1, 2, true)]);
+    let mut pixels = record(0, 0, InputShape::Circle(1)).pixels(CANVAS);
+    pixels.sort();
+    assert_eq!(pixels, [(-1, 0, true), (0, -1, true), (0, 0, true), (0, 1, true), (1, 0, true)]);
+    assert_eq!(record(0, 0, InputShape::Circle(5)).pixels(CANVAS).len(), 81);
+
+    // clipped to the canvas, with only the quarter at the corner left
+    let pixels = record(-10, -10, InputShape::Circle(5)).pixels(CANVAS);
+    assert_eq!(pixels.len(), 26);
+    assert!(pixels.iter().all(|(x, y, _)| (-10..-4).contains(x) && (-10..-4).contains(y)));
+    assert!(record(100, 100, InputShape::Circle(5)).pixels(CANVAS).is_empty());
+
+    // radii whose square overflows an i32
+    assert_eq!(record(0, 0, InputShape::Circle(u16::MAX)).pixels(CANVAS).len(), 400);
+    assert_eq!(record(i32::MAX, i32::MIN, InputShape::Circle(u16::MAX)).pixels(CANVAS).len(), 0);
+  }
+
+  #[test]
+  fn rectangles() {
+    assert_eq!(record(1, 2, InputShape::Rectangle(1, 2)).pixels(CANVAS), [(1, 2, true)]);
+    assert_eq!(
+      record(1, 2, InputShape::Rectangle(2, 3)).pixels(CANVAS),
+      [(1, 2, true), (2, 2, true), (1, 3, true), (2, 3, true)]
+    );
+    assert!(record(2, 2, InputShape::Rectangle(1, 1)).pixels(CANVAS).is_empty());
+
+    // clipped to the canvas
+    assert_eq!(record(-20, 8, InputShape::Rectangle(-9, 20)).pixels(CANVAS).len(), 4);
+    assert_eq!(record(i32::MIN, i32::MIN, InputShape::Rectangle(i32::MAX, i32::MAX)).pixels(CANVAS).len(), 400);
+    assert!(record(20, 20, InputShape::Rectangle(30, 30)).pixels(CANVAS).is_empty());
+    assert!(record(i32::MIN, 0, InputShape::Rectangle(-11, 0)).pixels(CANVAS).is_empty());
+  }
+
+  #[test]
+  fn circle_radius_limit() {
+    let headers = ["timestamp", "user_id", "pixel_color", "coordinate"];
+    let palette = [0xffffff];
+    let reader = reader(InputSchema::Reddit2022, &headers, Some(&palette));
+    let read = |coordinate: &str| reader.read(&ByteRecord::from(vec![
+      "2022-04-04 00:53:51.577 UTC", "abc", "#FFFFFF", coordinate
+    ])).map(|r| r.shape);
+
+    assert!(matches!(read("{X: 0, Y: 0, R: 1000}"), Ok(InputShape::Circle(1000))));
+    assert!(read("{X: 0, Y: 0, R: 1001}").is_err());
+    assert!(read("{X: 0, Y: 0, R: 50000}").is_err());
+  }
+
+  #[test]
+  fn split_coordinates() {
+    let headers = ["ts", "user_id", "x_coordinate", "y_coordinate", "x2_coordinate", "y2_coordinate", "color"];
+    let reader = reader(InputSchema::Plain, &headers, None);
+    let read = |x2: &str, y2: &str| reader.read(&ByteRecord::from(vec!["1", "2", "3", "4", x2, y2, "5"]))
+      .map(|r| r.shape);
+
+    assert!(matches!(read("", ""), Ok(InputShape::Pixel)));
+    assert!(matches!(read("6", ""), Ok(InputShape::Pixel)));
+    assert!(matches!(read("6", "7"), Ok(InputShape::Rectangle(6, 7))));
+    assert!(read("6", "x").is_err());
+  }
+
+  #[test]
+  fn rectangle_columns() {
+    let headers = ByteRecord::from(vec!["ts", "user_id", "x_coordinate", "y_coordinate", "color"]);
+    let plain = InputSchema::Plain.schema(&headers);
+    assert!(plain.x2.is_none() && plain.y2.is_none());
+    let reader = RecordReader::new(&plain, &headers, None, 1000).unwrap();
+    assert!(matches!(reader.read(&ByteRecord::from(vec!["1", "2", "3", "4", "5"])).map(|r| r.shape), Ok(InputShape::Pixel)));
+
+    // named columns missing from the input, or only one corner column
+    let schema = Schema { x2: Some(String::from("x2")), y2: Some(String::from("y2")), ..plain.clone() };
+    assert!(RecordReader::new(&schema, &headers, None, 1000).is_err());
+    let headers = ByteRecord::from(vec!["ts", "user_id", "x_coordinate", "y_coordinate", "x2", "y2", "color"]);
+    assert!(RecordReader::new(&schema, &headers, None, 1000).is_ok());
+    assert!(RecordReader::new(&Schema { y2: None, ..schema.clone() }, &headers, None, 1000).is_err());
+    assert!(RecordReader::new(&Schema { x2: None, ..schema }, &headers, None, 1000).is_err());
+  }
+}
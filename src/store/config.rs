@@ -1,10 +1,15 @@
-use glob::glob;
-use log::warn;
+use log::{info, warn};
 use serde::Deserialize;
-use std::iter;
+use std::fs::read_to_string;
+use std::{cmp, iter};
+use std::path::Path;
 
-use super::dataset::Dataset;
+use crate::error::{Error, Result};
+use crate::models::FrameEncoding;
+use crate::schema::Schema;
+use super::dataset::{CanvasArea, Dataset};
 use super::tile::Tile;
+use super::users::UserDictionary;
 
 #[derive(Debug, Deserialize)]
 pub struct ConfigRoot {
@@ -19,12 +24,119 @@ pub struct SerializedDataset {
   pub size_x: u16,
   pub size_y: u16,
   pub size_tile: u16,
+  // columns parse reads the dataset's input from
+  pub schema: Option<Schema>,
+  // how keyframe and parse store the dataset's keyframes
+  #[serde(default)]
+  pub frame_encoding: FrameEncoding,
+  // input coordinates of the top left pixel of the canvas, for inputs with
+  // the origin elsewhere
+  #[serde(default)]
+  pub origin_x: i32,
+  #[serde(default)]
+  pub origin_y: i32,
+  // parts of a canvas that grew over time and when they were opened. Without
+  // any the whole canvas is open from the start
+  #[serde(default)]
+  pub areas: Vec<SerializedArea>,
 }
 
+// The rectangle x1, y1 to x2, y2 inclusive in input coordinates, opened at
+// the unix timestamp in milliseconds.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SerializedArea {
+  pub from: u64,
+  pub x1: i32,
+  pub y1: i32,
+  pub x2: i32,
+  pub y2: i32,
+}
+
+impl ConfigRoot {
+  pub fn load(config_file: &str) -> Result<ConfigRoot> {
+    let config_str = read_to_string(config_file)
+      .map_err(Error::file(config_file))?;
+    Ok(serde_yaml::from_str(&config_str)?)
+  }
+
+  pub fn dataset(&self, name: &str) -> Result<&SerializedDataset> {
+    match self.datasets.iter().find(|d| d.name == name) {
+      Some(d) => Ok(d),
+      None => Err(Error::Config(format!("dataset {} not found", name)))
+    }
+  }
+}
 
 impl SerializedDataset {
-  pub fn load(&self) -> Dataset {
-    let palette: Vec<u8> = iter::once(0xffffff).chain(self.palette.clone().into_iter())
+  pub fn load(&self) -> Result<Dataset> {
+    let mut dataset = self.empty()?;
+    for ty in 0..self.size_y / self.size_tile {
+      for tx in 0..self.size_x / self.size_tile {
+        let mut tile = self.load_tile(tx, ty)?;
+        tile.set_areas(&dataset.areas);
+        dataset.tiles.push(tile);
+      }
+    }
+    self.warn_user_remainders(dataset.tiles.iter().filter(|t| !t.has_user_remainders()).count());
+    dataset.users = self.load_users()?;
+    if let Some(users) = dataset.users.as_ref() {
+      if let Some(problem) = check_users(&dataset.tiles, users) {
+        return Err(problem);
+      }
+    }
+    Ok(dataset)
+  }
+
+  // Loads every tile and checks its contents, collecting every problem found
+  // instead of stopping at the first. Only config errors are returned as Err.
+  pub fn verify(&self) -> Result<Vec<Error>> {
+    let dataset = self.empty()?;
+    let colors = dataset.trns_palette.len() - 1;
+    let mut problems = Vec::new();
+    let users = self.load_users().unwrap_or_else(|e| {
+      problems.push(e);
+      None
+    });
+    let mut missing = 0;
+    for ty in 0..self.size_y / self.size_tile {
+      for tx in 0..self.size_x / self.size_tile {
+        info!("verifying tile {},{} of {}", tx, ty, self.name);
+        match self.load_tile(tx, ty) {
+          Ok(tile) => {
+            missing += !tile.has_user_remainders() as usize;
+            problems.extend(tile.verify(colors));
+            problems.extend(users.as_ref().and_then(|users| check_users(&[tile], users)));
+          },
+          Err(e) => problems.push(e),
+        }
+      }
+    }
+    self.warn_user_remainders(missing);
+    Ok(problems)
+  }
+
+  // User remainder images are refused on tiles that would have to replay
+  // their whole log for them.
+  fn warn_user_remainders(&self, missing: usize) {
+    if missing > 0 {
+      warn!(
+        "{} tiles of {} have neither keyframes built with --uids nor user and pixel indexes, \
+        so user remainder images of them are unavailable", missing, self.name
+      );
+    }
+  }
+
+  // The dataset without any tiles loaded.
+  fn empty(&self) -> Result<Dataset> {
+    if self.size_tile == 0 || !self.size_x.is_multiple_of(self.size_tile) ||
+      !self.size_y.is_multiple_of(self.size_tile) {
+      return Err(Error::Config(format!(
+        "the size of dataset {} must be divisible by the tile size", self.name
+      )));
+    }
+
+    let palette: Vec<u8> = iter::once(0xffffff).chain(self.palette.clone())
       .flat_map(|v| {
         [
           (v >> 16 & 0xff) as u8,
@@ -34,61 +146,92 @@ impl SerializedDataset {
       })
       .collect();
     let trns_palette: Vec<u8> = iter::once(0)
-      .chain(iter::repeat(255).take(self.palette.len()))
+      .chain(iter::repeat_n(255, self.palette.len()))
       .collect();
 
+    let areas = self.areas.iter()
+      .map(|area| self.canvas_area(area))
+      .collect::<Result<Vec<_>>>()?;
+
     let tiles_x = (self.size_x / self.size_tile) as usize;
     let tiles_y = (self.size_y / self.size_tile) as usize;
 
-    let mut dataset = Dataset {
+    Ok(Dataset {
       name: self.name.clone(),
-      palette: palette,
-      trns_palette: trns_palette,
+      palette,
+      trns_palette,
       size_x: self.size_x,
       size_y: self.size_y,
       size_tile: self.size_tile,
+      origin_x: self.origin_x,
+      origin_y: self.origin_y,
+      areas,
       tiles: Vec::with_capacity(tiles_x * tiles_y),
-    };
-
-    let mut placement_files: Vec<String> = Vec::new();
-    let mut frame_files: Vec<String> = Vec::new();
+      users: None,
+    })
+  }
 
-    for entry in glob(&format!("{}_log_*_*.bin", self.prefix)).expect("Failed to read glob pattern") {
-      let filename = match entry {
-        Ok(s) => s,
-        Err(e) => {
-          warn!("Error with glob {}", e);
-          continue
-        }
-      };
-      placement_files.push(String::from(filename.to_str().unwrap()));
+  // The part of the canvas an area covers.
+  fn canvas_area(&self, area: &SerializedArea) -> Result<CanvasArea> {
+    let x0 = cmp::max(area.x1 as i64 - self.origin_x as i64, 0);
+    let y0 = cmp::max(area.y1 as i64 - self.origin_y as i64, 0);
+    let x1 = cmp::min(area.x2 as i64 - self.origin_x as i64 + 1, self.size_x as i64);
+    let y1 = cmp::min(area.y2 as i64 - self.origin_y as i64 + 1, self.size_y as i64);
+    if x0 >= x1 || y0 >= y1 {
+      return Err(Error::Config(format!(
+        "area {},{} to {},{} of dataset {} is not on the canvas", area.x1, area.y1, area.x2, area.y2, self.name
+      )));
     }
+    Ok(CanvasArea { from: area.from, x0: x0 as u16, y0: y0 as u16, x1: x1 as u16, y1: y1 as u16 })
+  }
 
-    for entry in glob(&format!("{}_frame_*_*.bin", self.prefix)).expect("Failed to read glob pattern") {
-      let filename = match entry {
-        Ok(s) => s,
-        Err(e) => {
-          warn!("Error with glob {}", e);
-          continue
-        }
-      };
-      frame_files.push(String::from(filename.to_str().unwrap()));
+  // The dictionary of original user ids, if parse gave users ids.
+  fn load_users(&self) -> Result<Option<UserDictionary>> {
+    let filename = format!("{}_users.bin", self.prefix);
+    if !Path::new(&filename).exists() {
+      return Ok(None);
     }
+    Ok(Some(UserDictionary::load(&filename)?))
+  }
 
-    if frame_files.len() != placement_files.len() {
-      // TODO: use a result for this
-      panic!("placement tiles don't correspond with frame tiles");
+  // Loads the tile at tx, ty in the grid with whichever indexes have been
+  // generated, checking it sits where the config expects.
+  fn load_tile(&self, tx: u16, ty: u16) -> Result<Tile> {
+    let placement_file = format!("{}_log_{}_{}.bin", self.prefix, tx, ty);
+    let frame_file = format!("{}_frame_{}_{}.bin", self.prefix, tx, ty);
+    for filename in [&placement_file, &frame_file] {
+      if !Path::new(filename).exists() {
+        return Err(Error::MissingTile { x: tx, y: ty, filename: filename.clone() });
+      }
+    }
+    let mut tile = Tile::load_with_frames(&placement_file, &frame_file)?;
+    if tile.size != self.size_tile || tile.start_x != tx * self.size_tile ||
+      tile.start_y != ty * self.size_tile {
+      return Err(Error::SizeMismatch(format!(
+        "{} is a {} pixel tile at {},{}, expected a {} pixel tile at {},{}",
+        placement_file, tile.size, tile.start_x, tile.start_y,
+        self.size_tile, tx * self.size_tile, ty * self.size_tile
+      )));
     }
 
-    for (pf, ff) in placement_files.iter().zip(frame_files.iter()) {
-      dataset.tiles.push(match Tile::load_with_frames(&pf, &ff) {
-        Ok(t) => t,
-        Err(e) => panic!("{}", e)
-      });
+    // indexes are optional, only load the ones that have been generated
+    let pixel_index = format!("{}_pixidx_{}_{}.bin", self.prefix, tx, ty);
+    if Path::new(&pixel_index).exists() {
+      tile.load_pixel_index(&pixel_index)?;
+    }
+    let user_index = format!("{}_uididx_{}_{}.bin", self.prefix, tx, ty);
+    if Path::new(&user_index).exists() {
+      tile.load_user_index(&user_index)?;
     }
-    
-    dataset.tiles.sort_by_key(|t| t.start_x);
-    dataset.tiles.sort_by_key(|t| t.start_y);
-    return dataset;
+    Ok(tile)
   }
 }
+
+// Checks the dictionary has an original id for every user in the tiles.
+fn check_users(tiles: &[Tile], users: &UserDictionary) -> Option<Error> {
+  let tile = tiles.iter().find(|t| t.count > 0 && t.max_uid as usize >= users.len())?;
+  Some(Error::SizeMismatch(format!(
+    "tile {},{} has user ids up to {}, but only {} users have original ids",
+    tile.start_x / tile.size, tile.start_y / tile.size, tile.max_uid, users.len()
+  )))
+}
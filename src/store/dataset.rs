@@ -1,6 +1,42 @@
+use rayon::prelude::*;
 use serde::Serialize;
+use std::cmp;
 
-use super::tile::Tile;
+use crate::image::{downscale, upscale};
+use crate::models::FrameData;
+use crate::models::record::Placement;
+use super::tile::{Replay, Tile};
+use super::users::UserDictionary;
+
+// Side of a tile in the zoom pyramid in pixels
+pub const PYRAMID_TILE_SIZE: u16 = 256;
+// Number of zoom levels past one pixel per canvas pixel
+pub const PYRAMID_MAX_OVERZOOM: u32 = 4;
+
+#[derive(Debug, Serialize)]
+pub struct UserStats {
+  pub uid: u32,
+  // original id of the user, when ids were given while parsing
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub user_hash: Option<String>,
+  pub placements: u32,
+  // unix timestamps in milliseconds of the first and last placement
+  pub first: Option<u64>,
+  pub last: Option<u64>,
+  // number of placements of each colour index
+  pub colors: Vec<u32>,
+}
+
+// The rectangle [x0, x1) x [y0, y1) of the canvas, and the unix timestamp in
+// milliseconds it became part of the canvas.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct CanvasArea {
+  pub from: u64,
+  pub x0: u16,
+  pub y0: u16,
+  pub x1: u16,
+  pub y1: u16,
+}
 
 #[derive(Debug, Serialize)]
 pub struct Dataset {
@@ -10,16 +46,386 @@ pub struct Dataset {
   pub size_x: u16,
   pub size_y: u16,
   pub size_tile: u16,
+  // input coordinates of the top left pixel of the canvas
+  pub origin_x: i32,
+  pub origin_y: i32,
+  // when each part of a canvas that grew was opened, empty when the whole
+  // canvas was open from the start
+  pub areas: Vec<CanvasArea>,
   pub tiles: Vec<Tile>,
+  #[serde(skip_serializing)]
+  pub users: Option<UserDictionary>,
 }
 
 impl Dataset {
   pub fn get_tile(&self, x: u16, y: u16) -> Option<&Tile> {
     let sx = self.size_x / self.size_tile;
     let sy = self.size_y / self.size_tile;
-    if x >= sx || y >= sy { 
+    if x >= sx || y >= sy {
+      return None
+    }
+    Some(&self.tiles[x as usize + y as usize * sx as usize])
+  }
+
+  // The canvas pixel at the input coordinates x, y, or None if it's off the
+  // canvas.
+  pub fn canvas_position(&self, x: i32, y: i32) -> Option<(u16, u16)> {
+    let (cx, cy) = (x as i64 - self.origin_x as i64, y as i64 - self.origin_y as i64);
+    if cx < 0 || cy < 0 || cx >= self.size_x as i64 || cy >= self.size_y as i64 {
+      return None
+    }
+    Some((cx as u16, cy as u16))
+  }
+
+  // The rectangle [x0, x1) x [y0, y1) in input coordinates as canvas
+  // coordinates, or None if it's empty or not entirely on the canvas.
+  pub fn canvas_region(&self, x0: i32, y0: i32, x1: i32, y1: i32) -> Option<(u16, u16, u16, u16)> {
+    let (cx0, cy0) = self.canvas_position(x0, y0)?;
+    let (cx1, cy1) = (x1 as i64 - self.origin_x as i64, y1 as i64 - self.origin_y as i64);
+    if cx1 <= cx0 as i64 || cy1 <= cy0 as i64 || cx1 > self.size_x as i64 || cy1 > self.size_y as i64 {
+      return None
+    }
+    Some((cx0, cy0, cx1 as u16, cy1 as u16))
+  }
+
+  // Finds the tile containing the canvas pixel at x, y and its placements.
+  pub fn get_pixel_history(&self, x: u16, y: u16) -> Option<(&Tile, Vec<Placement>)> {
+    let tile = self.get_tile(x / self.size_tile, y / self.size_tile)?;
+    Some((tile, tile.get_pixel_history(x - tile.start_x, y - tile.start_y)?))
+  }
+
+  // Looks up a user by their integer id, or their original id if ids were
+  // given while parsing.
+  pub fn resolve_user(&self, user: &str) -> Option<u32> {
+    user.parse().ok().or_else(|| self.users.as_ref()?.uid(user))
+  }
+
+  // Original id of the user, if ids were given while parsing.
+  pub fn user_hash(&self, user_id: u32) -> Option<String> {
+    Some(String::from(self.users.as_ref()?.name(user_id)?))
+  }
+
+  pub fn get_user_stats(&self, user_id: u32) -> UserStats {
+    let mut stats = UserStats {
+      uid: user_id,
+      user_hash: self.user_hash(user_id),
+      placements: 0,
+      first: None,
+      last: None,
+      colors: vec![0; self.palette.len() / 3 - 1],
+    };
+    for tile in self.tiles.iter() {
+      for p in tile.get_user_placements(user_id).unwrap_or_default() {
+        let ts = tile.start + p.ts as u64;
+        stats.placements += 1;
+        stats.first = Some(stats.first.map_or(ts, |t| cmp::min(t, ts)));
+        stats.last = Some(stats.last.map_or(ts, |t| cmp::max(t, ts)));
+        if let Some(c) = stats.colors.get_mut(p.color as usize) {
+          *c += 1;
+        }
+      }
+    }
+    stats
+  }
+
+  // Canvas coordinates of every pixel the user placed on, for each tile they
+  // touched.
+  pub fn get_user_pixels(&self, user_id: u32) -> Vec<(&Tile, Vec<(u16, u16)>)> {
+    self.tiles.par_iter()
+      .map(|tile| {
+        let mut pixels: Vec<(u16, u16)> = tile.get_user_placements(user_id).unwrap_or_default().iter()
+          .map(|p| (tile.start_x + p.x, tile.start_y + p.y))
+          .collect();
+        pixels.sort_by_key(|(x, y)| (*y, *x));
+        pixels.dedup();
+        (tile, pixels)
+      })
+      .filter(|(_, pixels)| !pixels.is_empty())
+      .collect()
+  }
+
+  // Stitches every tile's image of the user's placements into an indexed
+  // image of the whole canvas.
+  pub fn get_image_for_user(&self, user_id: u32) -> Option<Vec<u8>> {
+    let images: Vec<_> = self.tiles.par_iter()
+      .filter_map(|t| Some((t, t.get_image_for_user(user_id)?)))
+      .collect();
+    if images.is_empty() {
+      return None
+    }
+
+    let mut canvas = vec![0u8; self.size_x as usize * self.size_y as usize];
+    for (tile, image) in images.iter() {
+      crop_into(&mut canvas, 0, 0, self.size_x, self.size_y, tile, image);
+    }
+    Some(canvas)
+  }
+
+  // Renders the whole canvas at the timestamp.
+  pub fn get_image_at_timestamp(&self, timestamp: u64) -> Option<Vec<u8>> {
+    self.render_region(0, 0, self.size_x, self.size_y, timestamp)
+  }
+
+  // Renders the rectangle [x0, x1) x [y0, y1) of the canvas at the timestamp
+  // into an indexed image.
+  pub fn render_region(&self, x0: u16, y0: u16, x1: u16, y1: u16, timestamp: u64) -> Option<Vec<u8>> {
+    Some(self.replay_region(x0, y0, x1, y1, timestamp)?.image())
+  }
+
+  // Zoom level at which a pyramid tile shows one canvas pixel per pixel. At
+  // level 0 the whole canvas fits in a single pyramid tile.
+  pub fn native_zoom(&self) -> u32 {
+    let side = cmp::max(self.size_x, self.size_y) as u32;
+    let mut zoom = 0;
+    while (PYRAMID_TILE_SIZE as u32) << zoom < side {
+      zoom += 1;
+    }
+    zoom
+  }
+
+  // Renders tile x, y at zoom level z of the pyramid. Below the native level
+  // blocks of canvas pixels are reduced to their most common colour, above it
+  // pixels are upscaled. Areas past the edge of the canvas are transparent.
+  pub fn render_pyramid_tile(&self, z: u32, x: u32, y: u32, timestamp: u64) -> Option<Vec<u8>> {
+    let native = self.native_zoom();
+    if z > native + PYRAMID_MAX_OVERZOOM {
+      return None
+    }
+    let (span, down, up) = if z <= native {
+      let factor = 1u32 << (native - z);
+      (PYRAMID_TILE_SIZE as u32 * factor, factor as u16, 1)
+    } else {
+      let factor = 1u32 << (z - native);
+      (PYRAMID_TILE_SIZE as u32 / factor, 1, factor as u16)
+    };
+
+    let (x0, y0) = (x as u64 * span as u64, y as u64 * span as u64);
+    if x0 >= self.size_x as u64 || y0 >= self.size_y as u64 {
+      return None
+    }
+    let (x0, y0) = (x0 as u16, y0 as u16);
+    let x1 = cmp::min(x0 as u32 + span, self.size_x as u32) as u16;
+    let y1 = cmp::min(y0 as u32 + span, self.size_y as u32) as u16;
+
+    let region = self.render_region(x0, y0, x1, y1, timestamp)?;
+    let (image, width, height) = if down > 1 {
+      downscale(&region, x1 - x0, y1 - y0, down)
+    } else {
+      (upscale(&region, x1 - x0, y1 - y0, up), (x1 - x0) * up, (y1 - y0) * up)
+    };
+
+    let size = PYRAMID_TILE_SIZE as usize;
+    let mut output = vec![0u8; size * size];
+    for (row, line) in image.chunks(width as usize).take(height as usize).enumerate() {
+      output[row * size..row * size + width as usize].copy_from_slice(line);
+    }
+    Some(output)
+  }
+
+  // Starts an incremental replay of the rectangle [x0, x1) x [y0, y1). Only
+  // the tiles intersecting the rectangle are replayed, in parallel.
+  pub fn replay_region(&self, x0: u16, y0: u16, x1: u16, y1: u16, timestamp: u64) -> Option<RegionReplay<'_>> {
+    if x0 >= x1 || y0 >= y1 || x1 > self.size_x || y1 > self.size_y {
       return None
     }
-    return Some(&self.tiles[x as usize + y as usize * sx as usize]);
+
+    let replays: Vec<_> = self.tiles.par_iter()
+      .filter(|t| t.start_x < x1 && t.start_x + t.size > x0 && t.start_y < y1 && t.start_y + t.size > y0)
+      .map(|t| t.replay(timestamp))
+      .collect();
+
+    Some(RegionReplay {
+      x0,
+      y0,
+      x1,
+      y1,
+      replays: replays.into_iter().collect::<Option<Vec<_>>>()?,
+    })
+  }
+}
+
+pub struct RegionReplay<'a> {
+  x0: u16,
+  y0: u16,
+  x1: u16,
+  y1: u16,
+  replays: Vec<Replay<'a>>,
+}
+
+impl<'a> RegionReplay<'a> {
+  pub fn width(&self) -> u16 {
+    self.x1 - self.x0
+  }
+
+  pub fn height(&self) -> u16 {
+    self.y1 - self.y0
+  }
+
+  pub fn advance(&mut self, timestamp: u64) {
+    self.replays.par_iter_mut().for_each(|r| r.advance(timestamp));
+  }
+
+  // Consumes the replay, yielding an image of the region for each of count
+  // steps from the timestamp, as counted by timelapse_frames.
+  pub fn timelapse(mut self, from: u64, step: u64, count: u32) -> impl ExactSizeIterator<Item = Vec<u8>> + 'a {
+    (0..count).map(move |i| {
+      if i > 0 {
+        self.advance(from + i as u64 * step);
+      }
+      self.image()
+    })
+  }
+
+  // Crops the replayed tiles into an indexed image of the region.
+  pub fn image(&self) -> Vec<u8> {
+    let mut region = vec![0u8; self.width() as usize * self.height() as usize];
+    for replay in self.replays.iter() {
+      crop_into(&mut region, self.x0, self.y0, self.x1, self.y1, replay.tile(), &replay.image());
+    }
+    region
+  }
+}
+
+// Copies the part of a tile's image inside the rectangle [x0, x1) x [y0, y1)
+// of the canvas into an indexed image of the rectangle.
+fn crop_into(region: &mut [u8], x0: u16, y0: u16, x1: u16, y1: u16, tile: &Tile, image: &FrameData) {
+  let width = (x1 - x0) as usize;
+  let size = tile.size as usize;
+  let (cx0, cx1) = (cmp::max(x0, tile.start_x), cmp::min(x1, tile.start_x + tile.size));
+  let (cy0, cy1) = (cmp::max(y0, tile.start_y), cmp::min(y1, tile.start_y + tile.size));
+  if cx0 >= cx1 {
+    return
+  }
+  let len = (cx1 - cx0) as usize;
+  for y in cy0..cy1 {
+    let src = (y - tile.start_y) as usize * size + (cx0 - tile.start_x) as usize;
+    let dst = (y - y0) as usize * width + (cx0 - x0) as usize;
+    region[dst..dst + len].copy_from_slice(&image[src..src + len]);
+  }
+}
+
+// Number of frames in a timelapse between two timestamps inclusive, or None
+// if the timestamps are out of order or the step is 0.
+pub fn timelapse_frames(from: u64, to: u64, step: u64) -> Option<u64> {
+  to.checked_sub(from)?.checked_div(step)?.checked_add(1)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::tile::tests::load_log;
+
+  // A dataset with no tiles loaded, with its top left pixel at origin_x,
+  // origin_y in input coordinates.
+  fn dataset(size_x: u16, size_y: u16, origin_x: i32, origin_y: i32) -> Dataset {
+    Dataset {
+      name: String::from("test"),
+      palette: Vec::new(),
+      trns_palette: Vec::new(),
+      size_x,
+      size_y,
+      size_tile: size_x,
+      origin_x,
+      origin_y,
+      areas: Vec::new(),
+      tiles: Vec::new(),
+      users: None,
+    }
+  }
+
+  #[test]
+  fn input_coordinates() {
+    let centred = dataset(300, 200, -150, -100);
+    assert_eq!(centred.canvas_position(-150, -100), Some((0, 0)));
+    assert_eq!(centred.canvas_position(0, 0), Some((150, 100)));
+    assert_eq!(centred.canvas_position(149, 99), Some((299, 199)));
+    assert_eq!(centred.canvas_position(150, 0), None);
+    assert_eq!(centred.canvas_position(-151, 0), None);
+    assert_eq!(centred.canvas_position(i32::MIN, i32::MAX), None);
+
+    assert_eq!(centred.canvas_region(-10, -20, 10, 20), Some((140, 80, 160, 120)));
+    assert_eq!(centred.canvas_region(-150, -100, 150, 100), Some((0, 0, 300, 200)));
+    assert_eq!(centred.canvas_region(-150, -100, 151, 100), None);
+    assert_eq!(centred.canvas_region(-160, 0, 0, 10), None);
+    assert_eq!(centred.canvas_region(5, 0, 5, 10), None);
+    assert_eq!(centred.canvas_region(0, 0, i32::MAX, 10), None);
+
+    let plain = dataset(300, 200, 0, 0);
+    assert_eq!(plain.canvas_region(0, 0, 300, 200), Some((0, 0, 300, 200)));
+    assert_eq!(plain.canvas_region(-1, 0, 10, 10), None);
+  }
+
+  #[test]
+  fn crops_tiles_into_regions() {
+    let tile = load_log(2, 2, 2, &[]);
+    let image = vec![1, 2, 3, 4];
+
+    // a region over the tile's left column, running past its bottom edge
+    let mut region = vec![0; 2 * 3];
+    crop_into(&mut region, 1, 1, 3, 4, &tile, &image);
+    assert_eq!(region, [0, 0, 0, 1, 0, 3]);
+
+    // a region inside the tile
+    let mut region = vec![0; 1];
+    crop_into(&mut region, 3, 3, 4, 4, &tile, &image);
+    assert_eq!(region, [4]);
+
+    // regions beside and above the tile are untouched
+    for (x0, y0, x1, y1) in [(0, 2, 2, 4), (4, 2, 6, 4), (2, 0, 4, 2), (0, 0, 2, 2)] {
+      let mut region = vec![0; 4];
+      crop_into(&mut region, x0, y0, x1, y1, &tile, &image);
+      assert_eq!(region, [0; 4], "region {},{} {},{}", x0, y0, x1, y1);
+    }
+  }
+
+  #[test]
+  fn downscales_pyramid_tiles() {
+    let place = |ts: u32, x: u16, y: u16, color: u8| Placement { ts, uid: 0, x, y, color, isblk: 0 };
+    // a 600x300 canvas, native at zoom 2 where 1024 pixels fit
+    let mut placements = Vec::new();
+    // the 4x4 block at 0,0 is mostly colour 1, with a column of colour 2
+    for y in 0..4 {
+      for x in 0..4 {
+        placements.push(place(placements.len() as u32, x, y, if x == 3 { 2 } else { 1 }));
+      }
+    }
+    // the block at 4,0 ties blank and colour 2, so the lower index wins
+    for y in 0..2 {
+      for x in 4..8 {
+        placements.push(place(placements.len() as u32, x, y, 2));
+      }
+    }
+    let mut canvas = dataset(600, 300, 0, 0);
+    canvas.size_tile = 300;
+    canvas.tiles = vec![
+      load_log(0, 0, 300, &placements),
+      load_log(300, 0, 300, &[place(0, 299, 299, 2)]),
+    ];
+    assert_eq!(canvas.native_zoom(), 2);
+    let size = PYRAMID_TILE_SIZE as usize;
+    let ts = 1_000_000;
+
+    // at zoom 0 each pixel is a 4x4 block, and the canvas fills 150x75
+    let tile = canvas.render_pyramid_tile(0, 0, 0, ts).unwrap();
+    assert_eq!(tile.len(), size * size);
+    assert_eq!(tile[..3], [2, 1, 1]);
+    assert_eq!(tile[74 * size + 149], 1);
+    assert_eq!(tile[74 * size + 150], 0);
+    assert_eq!(tile[75 * size], 0);
+    assert!(canvas.render_pyramid_tile(0, 1, 0, ts).is_none());
+
+    // at the native zoom the tile at the corner is cropped to 88x44
+    let tile = canvas.render_pyramid_tile(2, 2, 1, ts).unwrap();
+    assert_eq!(tile[43 * size + 87], 3);
+    assert_eq!(tile[43 * size + 86], 1);
+    assert_eq!(tile[43 * size + 88], 0);
+    assert_eq!(tile[44 * size], 0);
+    assert!(canvas.render_pyramid_tile(2, 3, 0, ts).is_none());
+
+    // past it pixels are upscaled
+    let tile = canvas.render_pyramid_tile(3, 0, 0, ts).unwrap();
+    assert_eq!(tile[..8], [2, 2, 2, 2, 2, 2, 3, 3]);
+    assert_eq!(tile[size..size + 8], [2, 2, 2, 2, 2, 2, 3, 3]);
+    assert!(canvas.render_pyramid_tile(2 + PYRAMID_MAX_OVERZOOM + 1, 0, 0, ts).is_none());
   }
 }
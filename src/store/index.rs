@@ -0,0 +1,122 @@
+use log::info;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::error::{Error, Result};
+use crate::models::record::{get_u32, write_record, Placement, Record};
+use super::tile::Tile;
+
+// Placement indices grouped by a key such as the pixel or user id. Stored as
+// key_count + 1 little endian u32 offsets followed by the u32 placement
+// indices, where offsets[k]..offsets[k + 1] is the range of indices for key k.
+pub struct GroupedIndex<'a> {
+  offsets: &'a [u8],
+  indices: &'a [u8],
+}
+
+// The placement indices stored for one key.
+#[derive(Clone, Copy)]
+pub struct IndexEntries<'a>(&'a [u8]);
+
+impl<'a> GroupedIndex<'a> {
+  // Views an index stored at the start of data.
+  pub fn new(data: &'a [u8], key_count: usize, count: usize) -> GroupedIndex<'a> {
+    let (offsets, rest) = data.split_at((key_count + 1) * 4);
+    GroupedIndex { offsets, indices: &rest[..count * 4] }
+  }
+
+  fn offset(&self, key: usize) -> usize {
+    get_u32(self.offsets, key * 4) as usize
+  }
+
+  // Whether the offsets are in order and cover exactly the stored indices,
+  // and every index is in range, which get and its callers rely on.
+  pub fn is_valid(&self) -> bool {
+    let count = self.indices.len() / 4;
+    let key_count = self.offsets.len() / 4 - 1;
+    self.offset(0) == 0 &&
+      (0..key_count).all(|k| self.offset(k) <= self.offset(k + 1)) &&
+      self.offset(key_count) == count &&
+      IndexEntries(self.indices).iter().all(|i| (i as usize) < count)
+  }
+
+  // Placement indices for the key in increasing order.
+  pub fn get(&self, key: usize) -> IndexEntries<'a> {
+    if (key + 2) * 4 > self.offsets.len() {
+      return IndexEntries(&[]);
+    }
+    IndexEntries(&self.indices[self.offset(key) * 4..self.offset(key + 1) * 4])
+  }
+}
+
+impl<'a> IndexEntries<'a> {
+  pub fn len(&self) -> usize {
+    self.0.len() / 4
+  }
+
+  pub fn get(&self, n: usize) -> u32 {
+    get_u32(self.0, n * 4)
+  }
+
+  pub fn iter(&self) -> impl ExactSizeIterator<Item = u32> + 'a {
+    self.0.chunks_exact(4).map(|v| get_u32(v, 0))
+  }
+
+  // Number of leading entries matching pred, as with slice::partition_point.
+  pub fn partition_point<F: Fn(u32) -> bool>(&self, pred: F) -> usize {
+    let (mut lo, mut hi) = (0, self.len());
+    while lo < hi {
+      let mid = lo + (hi - lo) / 2;
+      if pred(self.get(mid)) {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    lo
+  }
+}
+
+// Groups the index of every key by key, returning the offsets and indices.
+pub fn build_index<I: Iterator<Item = usize> + Clone>(keys: I, key_count: usize) -> (Vec<u32>, Vec<u32>) {
+  let mut offsets = vec![0u32; key_count + 1];
+  let mut count = 0;
+  for key in keys.clone() {
+    offsets[key + 1] += 1;
+    count += 1;
+  }
+  for i in 1..offsets.len() {
+    offsets[i] += offsets[i - 1];
+  }
+
+  let mut indices = vec![0u32; count];
+  let mut next = offsets.clone();
+  for (i, key) in keys.enumerate() {
+    indices[next[key] as usize] = i as u32;
+    next[key] += 1;
+  }
+  (offsets, indices)
+}
+
+pub fn write_index<T: Write>(offsets: &[u32], indices: &[u32], w: &mut T) -> io::Result<()> {
+  w.write_all(&(offsets.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>()))?;
+  w.write_all(&(indices.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>()))
+}
+
+// Groups a tile's placements by key, where keys run from 0 to key_count, and
+// writes the header followed by the index to out_path.
+pub fn write_tile_index<H: Record + fmt::Debug, K: Fn(&Placement) -> usize>(
+  tile: &Tile, header: &H, key_count: usize, key: K, out_path: &Path
+) -> Result<()> {
+  let keys: Vec<usize> = tile.placements().iter().map(|p| Ok(key(&p?))).collect::<Result<_>>()?;
+  let (offsets, indices) = build_index(keys.iter().copied(), key_count);
+
+  let fw = File::create(out_path).map_err(Error::file(out_path))?;
+  let mut w = BufWriter::new(fw);
+  info!("Writing out {:?} with header {:?}", out_path, header);
+  write_record(header, &mut w)?;
+  write_index(&offsets, &indices, &mut w)?;
+  w.flush()?;
+  Ok(())
+}
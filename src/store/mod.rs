@@ -1,3 +1,6 @@
 pub mod config;
 pub mod dataset;
-pub mod tile;
\ No newline at end of file
+pub mod index;
+pub mod placements;
+pub mod tile;
+pub mod users;
\ No newline at end of file
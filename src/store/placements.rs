@@ -0,0 +1,505 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::{slice, vec};
+
+use crate::error::{Error, Result};
+use crate::models::record::{get_u32, get_u64, Placement};
+
+pub const COMPACT_BLOCK_SIZE: u32 = 4096;
+const COMPACT_ZSTD_LEVEL: i32 = 3;
+const COMPACT_TRAILER_SIZE: usize = 16;
+// colours are packed into 5 bits
+pub const COMPACT_MAX_COLORS: usize = 32;
+
+// Compact placement logs hold blocks of up to per_block placements, each
+// compressed with zstd, followed by a table of block_count + 1 u64 offsets to
+// the blocks and the u32 timestamp of each block's first placement, then a
+// trailer of 0: per_block, 4: block_count, 8: table offset.
+//
+// Within a block each placement is the timestamp delta from the previous
+// placement (or the block's first timestamp) and the uid as LEB128 varints,
+// then x and y in the minimal bits for the tile size, the colour in 5 bits
+// and isblk in 1 bit, packed little endian into as few bytes as fit.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactLayout {
+  per_block: usize,
+  block_count: usize,
+  table: usize,
+  bits: u32,
+}
+
+fn position_bits(size: u16) -> u32 {
+  u16::BITS - size.saturating_sub(1).leading_zeros()
+}
+
+fn packed_bytes(bits: u32) -> usize {
+  (bits as usize * 2 + 6).div_ceil(8)
+}
+
+impl CompactLayout {
+  // Reads the layout of a compact log of count placements from the data
+  // following its header, checking the block table is consistent. Blocks are
+  // only decoded when read, so a corrupt block fails the read rather than the
+  // load.
+  pub fn read(filename: &str, data: &[u8], count: usize, size: u16) -> Result<CompactLayout> {
+    let corrupt = |msg: &str| Error::Corrupt(format!("{}: {}", filename, msg));
+    if data.len() < COMPACT_TRAILER_SIZE {
+      return Err(corrupt("missing compact log trailer"));
+    }
+    let trailer = data.len() - COMPACT_TRAILER_SIZE;
+    let layout = CompactLayout {
+      per_block: get_u32(data, trailer) as usize,
+      block_count: get_u32(data, trailer + 4) as usize,
+      table: get_u64(data, trailer + 8) as usize,
+      bits: position_bits(size),
+    };
+    if layout.per_block == 0 || layout.block_count != count.div_ceil(layout.per_block) {
+      return Err(corrupt("block count doesn't match the number of placements"));
+    }
+    if layout.table.checked_add((layout.block_count + 1) * 8 + layout.block_count * 4) != Some(trailer) {
+      return Err(corrupt("block table doesn't fit the file"));
+    }
+    let log = CompactLog { data, layout, len: count };
+    if log.offset(0) != 0 || log.offset(layout.block_count) != layout.table ||
+      (0..layout.block_count).any(|b| log.offset(b) > log.offset(b + 1)) ||
+      (1..layout.block_count).any(|b| log.first_ts(b - 1) > log.first_ts(b)) {
+      return Err(corrupt("block table is out of order"));
+    }
+    Ok(layout)
+  }
+}
+
+#[derive(Clone, Copy)]
+pub struct CompactLog<'a> {
+  data: &'a [u8],
+  layout: CompactLayout,
+  len: usize,
+}
+
+impl<'a> CompactLog<'a> {
+  pub fn new(data: &'a [u8], layout: CompactLayout, len: usize) -> CompactLog<'a> {
+    CompactLog { data, layout, len }
+  }
+
+  fn offset(&self, block: usize) -> usize {
+    get_u64(self.data, self.layout.table + block * 8) as usize
+  }
+
+  fn first_ts(&self, block: usize) -> u32 {
+    get_u32(self.data, self.layout.table + (self.layout.block_count + 1) * 8 + block * 4)
+  }
+
+  fn block_len(&self, block: usize) -> usize {
+    (self.len - block * self.layout.per_block).min(self.layout.per_block)
+  }
+
+  fn decode_block(&self, block: usize) -> Result<Vec<Placement>> {
+    let corrupt = |msg: &str| Error::Corrupt(format!("compact log block {}: {}", block, msg));
+    let compressed = &self.data[self.offset(block)..self.offset(block + 1)];
+    let raw = zstd::stream::decode_all(compressed).map_err(|e| corrupt(&e.to_string()))?;
+
+    let bits = self.layout.bits;
+    let mask = (1u64 << bits) - 1;
+    let packed = packed_bytes(bits);
+    let mut placements = Vec::with_capacity(self.block_len(block));
+    let mut ts = self.first_ts(block);
+    let mut pos = 0;
+    for _ in 0..self.block_len(block) {
+      let delta = read_varint(&raw, &mut pos).ok_or_else(|| corrupt("truncated block"))?;
+      let uid = read_varint(&raw, &mut pos).ok_or_else(|| corrupt("truncated block"))?;
+      let fields = raw.get(pos..pos + packed).ok_or_else(|| corrupt("truncated block"))?;
+      pos += packed;
+      let v = fields.iter().rev().fold(0u64, |v, b| v << 8 | *b as u64);
+      ts = ts.checked_add(delta).ok_or_else(|| corrupt("timestamp overflow"))?;
+      placements.push(Placement {
+        ts,
+        uid,
+        x: (v & mask) as u16,
+        y: (v >> bits & mask) as u16,
+        color: (v >> (bits * 2) & 0x1f) as u8,
+        isblk: (v >> (bits * 2 + 5) & 1) as u8,
+      });
+    }
+    Ok(placements)
+  }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u32> {
+  let mut v = 0u32;
+  for shift in (0..35).step_by(7) {
+    let b = *data.get(*pos)?;
+    *pos += 1;
+    v |= ((b & 0x7f) as u32).checked_shl(shift)?;
+    if b & 0x80 == 0 {
+      return Some(v);
+    }
+  }
+  None
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u32) {
+  while v >= 0x80 {
+    out.push((v & 0x7f) as u8 | 0x80);
+    v >>= 7;
+  }
+  out.push(v as u8);
+}
+
+// Writes a compact placement log block by block, after the caller has
+// written the header.
+pub struct CompactWriter {
+  bits: u32,
+  block: Vec<u8>,
+  block_len: u32,
+  prev_ts: u32,
+  offsets: Vec<u64>,
+  first_ts: Vec<u32>,
+}
+
+impl CompactWriter {
+  pub fn new(size: u16) -> CompactWriter {
+    CompactWriter {
+      bits: position_bits(size),
+      block: Vec::new(),
+      block_len: 0,
+      prev_ts: 0,
+      offsets: vec![0],
+      first_ts: Vec::new(),
+    }
+  }
+
+  pub fn push<W: Write>(&mut self, p: &Placement, w: &mut W) -> Result<()> {
+    if p.color as usize >= COMPACT_MAX_COLORS {
+      return Err(Error::InvalidArgument(format!(
+        "colour {} doesn't fit in a compact log, which has at most {} colours", p.color, COMPACT_MAX_COLORS
+      )));
+    }
+    if p.ts < self.prev_ts {
+      return Err(Error::InvalidArgument(String::from("compact logs need placements in time order")));
+    }
+    if self.block_len == 0 {
+      self.first_ts.push(p.ts);
+      self.prev_ts = p.ts;
+    }
+    write_varint(&mut self.block, p.ts - self.prev_ts);
+    self.prev_ts = p.ts;
+    write_varint(&mut self.block, p.uid);
+    let v = p.x as u64 | (p.y as u64) << self.bits | (p.color as u64) << (self.bits * 2) |
+      (p.is_blk() as u64) << (self.bits * 2 + 5);
+    self.block.extend_from_slice(&v.to_le_bytes()[..packed_bytes(self.bits)]);
+    self.block_len += 1;
+    if self.block_len == COMPACT_BLOCK_SIZE {
+      self.flush_block(w)?;
+    }
+    Ok(())
+  }
+
+  fn flush_block<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+    // checksummed so that corrupt blocks fail to decode rather than giving
+    // wrong placements
+    let mut encoder = zstd::Encoder::new(Vec::new(), COMPACT_ZSTD_LEVEL)?;
+    encoder.include_checksum(true)?;
+    encoder.write_all(&self.block)?;
+    let compressed = encoder.finish()?;
+    w.write_all(&compressed)?;
+    self.offsets.push(self.offsets[self.offsets.len() - 1] + compressed.len() as u64);
+    self.block.clear();
+    self.block_len = 0;
+    Ok(())
+  }
+
+  // Writes the last partial block, the block table and the trailer.
+  pub fn finish<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+    if self.block_len > 0 {
+      self.flush_block(w)?;
+    }
+    let table = self.offsets[self.offsets.len() - 1];
+    for offset in self.offsets.iter() {
+      w.write_all(&offset.to_le_bytes())?;
+    }
+    for ts in self.first_ts.iter() {
+      w.write_all(&ts.to_le_bytes())?;
+    }
+    w.write_all(&COMPACT_BLOCK_SIZE.to_le_bytes())?;
+    w.write_all(&(self.first_ts.len() as u32).to_le_bytes())?;
+    w.write_all(&table.to_le_bytes())
+  }
+}
+
+// Read access to a tile's placement log, which is either an array of
+// placements or a compact log decoded a block at a time. Reads of a compact
+// log fail with Error::Corrupt when a block can't be decoded.
+pub enum Placements<'a> {
+  Slice(&'a [Placement]),
+  // the last block decoded by get, as lookups tend to be close together
+  Compact(CompactLog<'a>, RefCell<Option<(usize, Vec<Placement>)>>),
+}
+
+impl<'a> Placements<'a> {
+  pub fn compact(log: CompactLog<'a>) -> Placements<'a> {
+    Placements::Compact(log, RefCell::new(None))
+  }
+
+  pub fn len(&self) -> usize {
+    match self {
+      Placements::Slice(s) => s.len(),
+      Placements::Compact(log, _) => log.len,
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn get(&self, idx: usize) -> Result<Placement> {
+    match self {
+      Placements::Slice(s) => Ok(s[idx]),
+      Placements::Compact(log, cache) => {
+        let (block, offset) = (idx / log.layout.per_block, idx % log.layout.per_block);
+        let mut cache = cache.borrow_mut();
+        match &*cache {
+          Some((cached, placements)) if *cached == block => Ok(placements[offset]),
+          _ => {
+            let placements = log.decode_block(block)?;
+            let p = placements[offset];
+            *cache = Some((block, placements));
+            Ok(p)
+          }
+        }
+      }
+    }
+  }
+
+  pub fn last(&self) -> Result<Option<Placement>> {
+    match self.len() {
+      0 => Ok(None),
+      len => self.get(len - 1).map(Some),
+    }
+  }
+
+  pub fn iter(&self) -> PlacementIter<'a> {
+    self.range(0..self.len())
+  }
+
+  pub fn range(&self, range: Range<usize>) -> PlacementIter<'a> {
+    match self {
+      Placements::Slice(s) => PlacementIter::Slice(s[range].iter()),
+      Placements::Compact(log, _) => {
+        assert!(range.start <= range.end && range.end <= log.len);
+        PlacementIter::Compact {
+          log: *log,
+          placements: Vec::new().into_iter(),
+          next_block: range.start / log.layout.per_block,
+          skip: range.start % log.layout.per_block,
+          remaining: range.len(),
+        }
+      }
+    }
+  }
+
+  // Index of the first placement at or after ts.
+  pub fn index_at_ts(&self, ts: u32) -> Result<usize> {
+    match self {
+      Placements::Slice(s) => Ok(s.partition_point(|p| p.ts < ts)),
+      Placements::Compact(log, _) => {
+        // the first placement at or after ts is in the last block starting
+        // before it, or starts the block after
+        let (mut block, mut hi) = (0, log.layout.block_count);
+        while block < hi {
+          let mid = block + (hi - block) / 2;
+          if log.first_ts(mid) < ts {
+            block = mid + 1;
+          } else {
+            hi = mid;
+          }
+        }
+        if block == 0 {
+          return Ok(0);
+        }
+        let placements = log.decode_block(block - 1)?;
+        Ok((block - 1) * log.layout.per_block + placements.partition_point(|p| p.ts < ts))
+      }
+    }
+  }
+}
+
+// Iterates over placements, decoding compact blocks as they are reached. A
+// block that can't be decoded yields its error and ends the iteration.
+#[derive(Clone)]
+pub enum PlacementIter<'a> {
+  Slice(slice::Iter<'a, Placement>),
+  Compact {
+    log: CompactLog<'a>,
+    placements: vec::IntoIter<Placement>,
+    next_block: usize,
+    // placements to skip at the start of the next block
+    skip: usize,
+    remaining: usize,
+  },
+}
+
+impl Iterator for PlacementIter<'_> {
+  type Item = Result<Placement>;
+
+  fn next(&mut self) -> Option<Result<Placement>> {
+    match self {
+      PlacementIter::Slice(iter) => iter.next().copied().map(Ok),
+      PlacementIter::Compact { log, placements, next_block, skip, remaining } => {
+        if *remaining == 0 {
+          return None;
+        }
+        if placements.len() == 0 {
+          match log.decode_block(*next_block) {
+            Ok(mut block) => {
+              block.drain(..*skip);
+              *placements = block.into_iter();
+            },
+            Err(e) => {
+              *remaining = 0;
+              return Some(Err(e));
+            }
+          }
+          *skip = 0;
+          *next_block += 1;
+        }
+        *remaining -= 1;
+        placements.next().map(Ok)
+      }
+    }
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    let len = match self {
+      PlacementIter::Slice(iter) => iter.len(),
+      PlacementIter::Compact { remaining, .. } => *remaining,
+    };
+    (len, Some(len))
+  }
+}
+
+impl ExactSizeIterator for PlacementIter<'_> {}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cmp;
+
+  fn encode(placements: &[Placement], size: u16) -> Vec<u8> {
+    let mut writer = CompactWriter::new(size);
+    let mut data = Vec::new();
+    for p in placements {
+      writer.push(p, &mut data).unwrap();
+    }
+    writer.finish(&mut data).unwrap();
+    data
+  }
+
+  // Placements in time order touching the corners of the tile and every
+  // colour, with a mix of small and large timestamp deltas.
+  fn sample(count: usize, size: u16) -> Vec<Placement> {
+    let mut ts = 0u32;
+    (0..count).map(|i| {
+      ts += match i % 7 { 0 => 0, 3 => 100_000, _ => i as u32 % 13 };
+      Placement {
+        ts,
+        uid: if i % 5 == 0 { u32::MAX - 1 } else { i as u32 },
+        x: if i % 2 == 0 { size - 1 } else { (i % size as usize) as u16 },
+        y: if i % 3 == 0 { size - 1 } else { 0 },
+        color: (i % COMPACT_MAX_COLORS) as u8,
+        isblk: (i % 4 == 0) as u8,
+      }
+    }).collect()
+  }
+
+  fn round_trip(count: usize, size: u16) {
+    let placements = sample(count, size);
+    let data = encode(&placements, size);
+    let layout = CompactLayout::read("test", &data, count, size).unwrap();
+    let log = Placements::compact(CompactLog::new(&data, layout, count));
+
+    assert_eq!(log.len(), count);
+    assert_eq!(log.iter().collect::<Result<Vec<_>>>().unwrap(), placements);
+    assert_eq!(log.last().unwrap(), placements.last().copied());
+    let per_block = COMPACT_BLOCK_SIZE as usize;
+    for i in [0, 1, per_block - 1, per_block, per_block + 1, count - 1].into_iter().filter(|i| *i < count) {
+      assert_eq!(log.get(i).unwrap(), placements[i], "placement {}", i);
+    }
+    for start in [0, per_block - 3, per_block, count - 1].into_iter().filter(|i| *i < count) {
+      let end = cmp::min(start + per_block + 7, count);
+      assert_eq!(log.range(start..end).len(), end - start);
+      assert_eq!(log.range(start..end).collect::<Result<Vec<_>>>().unwrap(), placements[start..end]);
+    }
+    for p in placements.iter().step_by(97).chain(placements.last()) {
+      for ts in [p.ts.saturating_sub(1), p.ts, p.ts + 1] {
+        assert_eq!(log.index_at_ts(ts).unwrap(), placements.partition_point(|q| q.ts < ts), "ts {}", ts);
+      }
+    }
+  }
+
+  #[test]
+  fn round_trip_across_blocks() {
+    round_trip(COMPACT_BLOCK_SIZE as usize * 2 + 5, 500);
+    round_trip(COMPACT_BLOCK_SIZE as usize, 500);
+    round_trip(3, 500);
+  }
+
+  #[test]
+  fn round_trip_tile_sizes() {
+    round_trip(COMPACT_BLOCK_SIZE as usize + 1, u16::MAX);
+    round_trip(100, 2);
+    round_trip(100, 1);
+    round_trip(100, 256);
+    round_trip(100, 257);
+  }
+
+  #[test]
+  fn highest_colour() {
+    let p = Placement { ts: 7, uid: 3, x: u16::MAX - 1, y: u16::MAX - 1, color: 31, isblk: 1 };
+    let data = encode(&[p], u16::MAX);
+    let layout = CompactLayout::read("test", &data, 1, u16::MAX).unwrap();
+    assert_eq!(Placements::compact(CompactLog::new(&data, layout, 1)).get(0).unwrap(), p);
+
+    let mut writer = CompactWriter::new(u16::MAX);
+    assert!(writer.push(&Placement { color: 32, ..p }, &mut Vec::new()).is_err());
+  }
+
+  #[test]
+  fn rejects_out_of_order() {
+    let mut writer = CompactWriter::new(16);
+    let p = Placement { ts: 10, uid: 0, x: 0, y: 0, color: 0, isblk: 0 };
+    writer.push(&p, &mut Vec::new()).unwrap();
+    assert!(writer.push(&Placement { ts: 9, ..p }, &mut Vec::new()).is_err());
+  }
+
+  #[test]
+  fn empty_log() {
+    let data = encode(&[], 500);
+    let layout = CompactLayout::read("test", &data, 0, 500).unwrap();
+    let log = Placements::compact(CompactLog::new(&data, layout, 0));
+    assert!(log.is_empty());
+    assert_eq!(log.iter().count(), 0);
+    assert_eq!(log.index_at_ts(5).unwrap(), 0);
+  }
+
+  #[test]
+  fn corrupt_block_fails_to_read() {
+    let count = COMPACT_BLOCK_SIZE as usize + 10;
+    let placements = sample(count, 500);
+    let mut data = encode(&placements, 500);
+    assert!(CompactLayout::read("test", &data, count + COMPACT_BLOCK_SIZE as usize, 500).is_err());
+
+    // only the block table is checked on load, so the first block fails when read
+    data[20] ^= 0xff;
+    let layout = CompactLayout::read("test", &data, count, 500).unwrap();
+    let log = Placements::compact(CompactLog::new(&data, layout, count));
+    let per_block = COMPACT_BLOCK_SIZE as usize;
+    assert!(matches!(log.get(0), Err(Error::Corrupt(_))));
+    assert!(matches!(log.index_at_ts(placements[1].ts), Err(Error::Corrupt(_))));
+    let mut iter = log.iter();
+    assert!(matches!(iter.next(), Some(Err(Error::Corrupt(_)))));
+    assert!(iter.next().is_none());
+    assert_eq!(log.get(per_block).unwrap(), placements[per_block]);
+    assert_eq!(log.range(per_block..count).collect::<Result<Vec<_>>>().unwrap(), placements[per_block..]);
+  }
+}
@@ -1,11 +1,19 @@
-use log::{debug, info};
+use log::{debug, error, info};
 use memmap::{Mmap, MmapOptions};
-use std::{cmp, mem, ptr, slice};
+use std::{cmp, fmt, slice};
+use std::borrow::Cow;
 use std::fs::File;
-use std::result::Result;
 use std::time::Instant;
-use crate::models::FrameData;
-use crate::models::record::{TileKeyframeHeader, TilePlacementHeader, Placement, TILE_PLACEMENT_VERSION_ID, TILE_KEYFRAME_VERSION_ID};
+use crate::error::{Error, Result};
+use crate::models::{FrameData, UidFrameData, NO_UID};
+use crate::models::record::{
+  decode_header, get_u32, get_u64, view_placements, Header, Placement, Record, TileKeyframeHeader,
+  TilePixelIndexHeader, TilePlacementHeader, TileUserIndexHeader, FORMAT_COLOUR_FRAMES, FORMAT_VERSION,
+  FRAME_FLAG_TABLE, FRAME_FLAG_UIDS, FRAME_FLAG_ZSTD, LOG_FLAG_COMPACT
+};
+use super::dataset::CanvasArea;
+use super::index::GroupedIndex;
+use super::placements::{CompactLayout, CompactLog, Placements};
 use serde::Serialize;
 
 
@@ -13,192 +21,867 @@ use serde::Serialize;
 pub struct Tile {
   pub start: u64,
   pub count: u32,
-  pub uid_count: u32,
+  // the largest uid placed on the tile, so there are max_uid + 1 user ids
+  pub max_uid: u32,
   pub start_x: u16,
   pub start_y: u16,
   pub size: u16,
   pub frame_count: u32,
   pub frame_interval: u32,
+  // unix timestamp in milliseconds the first part of the tile was opened
+  pub active_from: u64,
 
+  // the dataset's areas overlapping the tile, relative to it
   #[serde(skip_serializing)]
-  mmap_placements: Option<Mmap>,
+  areas: Vec<CanvasArea>,
 
   #[serde(skip_serializing)]
-  mmap_frames: Option<Mmap>,
+  placement_data: Option<PlacementData>,
+
+  #[serde(skip_serializing)]
+  mmap_frames: Option<MappedFile>,
+
+  #[serde(skip_serializing)]
+  frames_compressed: bool,
+
+  #[serde(skip_serializing)]
+  frame_layout: FrameLayout,
+
+  // with FRAME_FLAG_TABLE, the timestamp and first placement of each frame
+  #[serde(skip_serializing)]
+  frame_times: Option<Vec<(u32, u32)>>,
+
+  #[serde(skip_serializing)]
+  mmap_pixel_index: Option<MappedFile>,
+
+  #[serde(skip_serializing)]
+  mmap_user_index: Option<MappedFile>,
+}
+
+// A mapped tile file and where the data following its header starts, which
+// depends on the format it was written in.
+#[derive(Debug)]
+pub struct MappedFile {
+  mmap: Mmap,
+  offset: usize,
+  format: u16,
+}
+
+impl MappedFile {
+  pub fn data(&self) -> &[u8] {
+    &self.mmap[self.offset..]
+  }
+}
+
+// How the pixels of each keyframe are stored, see TileKeyframeHeader.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FrameLayout {
+  // before format 3, (uid << 8) + colour + 1 as a u32
+  Packed,
+  // a u8 colour plane for each frame, then a u32 uid plane for each frame
+  Planes { uids: bool },
+}
+
+impl FrameLayout {
+  // Number of separately stored frames for count keyframes.
+  fn sections(&self, count: usize) -> usize {
+    match self {
+      FrameLayout::Planes { uids: true } => count * 2,
+      _ => count,
+    }
+  }
+
+  // Byte length and uncompressed offset of the nth stored frame.
+  fn section(&self, n: usize, count: usize, pixels: usize) -> (usize, usize) {
+    match self {
+      FrameLayout::Packed => (pixels * 4, n * pixels * 4),
+      FrameLayout::Planes { .. } if n < count => (pixels, n * pixels),
+      FrameLayout::Planes { .. } => (pixels * 4, count * pixels + (n - count) * pixels * 4),
+    }
+  }
 }
 
+// Placement logs are used in place where the encoding matches the in-memory
+// layout, and decoded otherwise. Compact logs are decoded as they're read.
+#[derive(Debug)]
+enum PlacementData {
+  Mapped(MappedFile),
+  Decoded(Vec<Placement>),
+  Compact(MappedFile, CompactLayout),
+}
+
+fn map_file(filename: &str) -> Result<Mmap> {
+  let file = File::open(filename).map_err(Error::file(filename))?;
+  unsafe { MmapOptions::new().map(&file) }.map_err(Error::file(filename))
+}
+
+// Checks there are at least expected bytes following the header.
+pub fn check_length(filename: &str, file: &MappedFile, expected: usize) -> Result<()> {
+  if file.data().len() < expected {
+    return Err(Error::SizeMismatch(format!(
+      "{} has {} bytes after its header, expected at least {}", filename, file.data().len(), expected
+    )));
+  }
+  Ok(())
+}
+
+// Reads the header at the start of a file, returning it with its format and
+// size. Checks that it's the expected kind of file and in a format this
+// build can read.
+pub fn read_header<H: Header + fmt::Debug>(filename: &str, buf: &[u8]) -> Result<(H, u16, usize)> {
+  let (header, format, offset) = match decode_header::<H>(buf) {
+    Some(h) => h,
+    None => return Err(Error::SizeMismatch(format!(
+      "{} is too short for its header", filename
+    ))),
+  };
+  check_version(filename, H::VERSION, header.version())?;
+  if format > FORMAT_VERSION || header.flags() & !H::FLAGS != 0 {
+    return Err(Error::Unsupported(format!(
+      "{} is format {} with flags {:#x}, only up to format {} with flags {:#x} can be read",
+      filename, format, header.flags(), FORMAT_VERSION, H::FLAGS
+    )));
+  }
+  Ok((header, format, offset))
+}
+
+pub fn map_with_header<H: Header + fmt::Debug>(filename: &str) -> Result<(H, MappedFile)> {
+  let mmap = map_file(filename)?;
+  let (header, format, offset) = read_header::<H>(filename, &mmap)?;
+  info!("loading {:?} in format {} with header: {:?}", filename, format, header);
+  Ok((header, MappedFile { mmap, offset, format }))
+}
+
+fn check_version(filename: &str, expected: u16, found: u16) -> Result<()> {
+  if found != expected {
+    return Err(Error::BadVersion { filename: String::from(filename), expected, found });
+  }
+  Ok(())
+}
+
+// Checks that an index holds every placement exactly once, in order, under
+// the right key, where keys holds the key of each placement. Returns a
+// description of the first problem found.
+fn check_index(index: &GroupedIndex, key_count: usize, keys: &[usize]) -> Option<String> {
+  let mut seen = 0;
+  for k in 0..key_count {
+    let indices = index.get(k);
+    for (n, i) in indices.iter().enumerate() {
+      if n > 0 && i <= indices.get(n - 1) {
+        return Some(format!("has unordered entries for key {}", k));
+      }
+      if keys.get(i as usize) != Some(&k) {
+        return Some(format!("has a bad entry {} for key {}", i, k));
+      }
+    }
+    seen += indices.len();
+  }
+  if seen != keys.len() {
+    return Some(format!("covers {} of {} placements", seen, keys.len()));
+  }
+  None
+}
 
 impl Tile {
-  pub fn load(placement_filename: &str) -> Result<Tile, String> {
-    let file = match File::open(&placement_filename) {
-      Ok(f) => f,
-      Err(e) => {
-        return Err(e.to_string());
+  pub fn load(placement_filename: &str) -> Result<Tile> {
+    let (header, file) = map_with_header::<TilePlacementHeader>(placement_filename)?;
+    if header.size == 0 {
+      return Err(Error::SizeMismatch(format!("{} has a tile size of 0", placement_filename)));
+    }
+    let placement_data = if header.flags & LOG_FLAG_COMPACT != 0 {
+      let layout = CompactLayout::read(placement_filename, file.data(), header.count as usize, header.size)?;
+      PlacementData::Compact(file, layout)
+    } else {
+      let len = header.count as usize * Placement::SIZE;
+      check_length(placement_filename, &file, len)?;
+      match view_placements(&file.data()[..len]) {
+        Some(_) => PlacementData::Mapped(file),
+        None => PlacementData::Decoded(
+          file.data()[..len].chunks_exact(Placement::SIZE).map(Placement::decode).collect()
+        ),
       }
     };
-    let mmap = unsafe { MmapOptions::new().map(&file).unwrap() };
-    let header: TilePlacementHeader = unsafe { ptr::read(mmap.as_ptr() as *const _) };
-    info!("loading tile {:?} with header: {:?}", &placement_filename, header);
-    if header.version != TILE_PLACEMENT_VERSION_ID {
-      return Err(String::from("header version is wrong"));
-    }
-    
+
     Ok(Tile{
       start: header.start,
       count: header.count,
-      uid_count: header.uid_count,
+      max_uid: header.max_uid,
       start_x: header.start_x,
       start_y: header.start_y,
       size: header.size,
       frame_count: 0,
       frame_interval: 0,
-      mmap_placements: Some(mmap),
+      active_from: 0,
+      areas: Vec::new(),
+      placement_data: Some(placement_data),
       mmap_frames: None,
+      frames_compressed: false,
+      frame_layout: FrameLayout::Packed,
+      frame_times: None,
+      mmap_pixel_index: None,
+      mmap_user_index: None,
     })
   }
 
-  pub fn load_with_frames(placement_filename: &str, frame_filename: &str) -> Result<Tile, String> {
-    let placements_file = match File::open(&placement_filename) {
-      Ok(f) => f,
-      Err(e) => {
-        return Err(e.to_string());
+  pub fn load_with_frames(placement_filename: &str, frame_filename: &str) -> Result<Tile> {
+    let mut tile = Tile::load(placement_filename)?;
+
+    let (header_frames, mmap_frames) = map_with_header::<TileKeyframeHeader>(frame_filename)?;
+    if header_frames.interval == 0 || (header_frames.count == 0 && tile.count > 0) {
+      return Err(Error::SizeMismatch(format!(
+        "{} has {} frames every {} placements for {} placements",
+        frame_filename, header_frames.count, header_frames.interval, tile.count
+      )));
+    }
+    let layout = if mmap_frames.format < FORMAT_COLOUR_FRAMES {
+      FrameLayout::Packed
+    } else {
+      FrameLayout::Planes { uids: header_frames.flags & FRAME_FLAG_UIDS != 0 }
+    };
+    let count = header_frames.count as usize;
+    let sections = layout.sections(count);
+    let compressed = header_frames.flags & FRAME_FLAG_ZSTD != 0;
+    let frames_end = if compressed {
+      let table = (sections + 1) * 8;
+      check_length(frame_filename, &mmap_frames, table)?;
+      let data = mmap_frames.data();
+      let offsets: Vec<u64> = (0..=sections).map(|i| get_u64(data, i * 8)).collect();
+      if offsets[0] != 0 || offsets.windows(2).any(|w| w[0] > w[1]) {
+        return Err(Error::Corrupt(format!("{} has out of order frame offsets", frame_filename)));
       }
+      table + offsets[offsets.len() - 1] as usize
+    } else if sections > 0 {
+      let pixels = header_frames.size as usize * header_frames.size as usize;
+      let (len, offset) = layout.section(sections - 1, count, pixels);
+      offset + len
+    } else {
+      0
     };
+    check_length(frame_filename, &mmap_frames, frames_end)?;
 
-    let frame_file = match File::open(&frame_filename) {
-      Ok(f) => f,
-      Err(e) => {
-        return Err(e.to_string());
+    let frame_times = if header_frames.flags & FRAME_FLAG_TABLE != 0 {
+      check_length(frame_filename, &mmap_frames, frames_end + count * 8)?;
+      let data = &mmap_frames.data()[frames_end..];
+      let times: Vec<(u32, u32)> = (0..count).map(|i| (get_u32(data, i * 8), get_u32(data, i * 8 + 4))).collect();
+      if times.first().is_some_and(|t| t.1 != 0) ||
+        times.windows(2).any(|w| w[0].0 > w[1].0 || w[0].1 >= w[1].1) ||
+        times.last().is_some_and(|t| t.1 >= tile.count) {
+        return Err(Error::Corrupt(format!("{} has out of order frame times", frame_filename)));
       }
+      Some(times)
+    } else {
+      None
     };
 
-    let mmap_placements = unsafe { MmapOptions::new().map(&placements_file).unwrap() };
-    let header_placements: TilePlacementHeader = unsafe {
-      ptr::read(mmap_placements.as_ptr() as *const _)
+    if tile.start_x != header_frames.start_x ||
+      tile.start_y != header_frames.start_y ||
+      tile.size != header_frames.size {
+      return Err(Error::SizeMismatch(format!(
+        "header mismatch between placements {} and frames {}", placement_filename, frame_filename
+      )));
+    }
+
+    tile.frame_count = header_frames.count;
+    tile.frame_interval = header_frames.interval;
+    tile.mmap_frames = Some(mmap_frames);
+    tile.frames_compressed = compressed;
+    tile.frame_layout = layout;
+    tile.frame_times = frame_times;
+    Ok(tile)
+  }
+
+  pub fn load_pixel_index(&mut self, pixel_index_filename: &str) -> Result<()> {
+    let (header, mmap) = map_with_header::<TilePixelIndexHeader>(pixel_index_filename)?;
+    if header.start_x != self.start_x || header.start_y != self.start_y ||
+      header.size != self.size || header.count != self.count {
+      return Err(Error::SizeMismatch(format!(
+        "header mismatch between placements and pixel index {}", pixel_index_filename
+      )));
+    }
+    let pixels = self.size as usize * self.size as usize;
+    check_length(
+      pixel_index_filename, &mmap,
+      (pixels + 1 + self.count as usize) * 4
+    )?;
+
+    self.mmap_pixel_index = Some(mmap);
+    if !self.pixel_index().is_some_and(|index| index.is_valid()) {
+      self.mmap_pixel_index = None;
+      return Err(Error::Corrupt(format!("pixel index {} has bad offsets or entries", pixel_index_filename)));
+    }
+    Ok(())
+  }
+
+  pub fn load_user_index(&mut self, user_index_filename: &str) -> Result<()> {
+    let (header, mmap) = map_with_header::<TileUserIndexHeader>(user_index_filename)?;
+    if header.start_x != self.start_x || header.start_y != self.start_y ||
+      header.size != self.size || header.count != self.count || header.max_uid != self.max_uid {
+      return Err(Error::SizeMismatch(format!(
+        "header mismatch between placements and user index {}", user_index_filename
+      )));
+    }
+    check_length(
+      user_index_filename, &mmap,
+      (self.max_uid as usize + 2 + self.count as usize) * 4
+    )?;
+
+    self.mmap_user_index = Some(mmap);
+    if !self.user_index().is_some_and(|index| index.is_valid()) {
+      self.mmap_user_index = None;
+      return Err(Error::Corrupt(format!("user index {} has bad offsets or entries", user_index_filename)));
+    }
+    Ok(())
+  }
+
+  // Keeps the parts of the areas of a canvas that grew which overlap the
+  // tile. Without areas the tile is open from the start.
+  pub fn set_areas(&mut self, areas: &[CanvasArea]) {
+    let (x0, y0, size) = (self.start_x, self.start_y, self.size);
+    self.areas = areas.iter()
+      .filter(|a| a.x0 < x0 + size && a.x1 > x0 && a.y0 < y0 + size && a.y1 > y0)
+      .map(|a| CanvasArea {
+        from: a.from,
+        x0: a.x0.saturating_sub(x0),
+        y0: a.y0.saturating_sub(y0),
+        x1: cmp::min(a.x1 - x0, size),
+        y1: cmp::min(a.y1 - y0, size),
+      })
+      .collect();
+    self.active_from = match areas.is_empty() {
+      true => 0,
+      false => self.areas.iter().map(|a| a.from).min().unwrap_or(u64::MAX),
     };
-    info!("loading placement {:?} with header: {:?}", &placement_filename, header_placements);
-    if header_placements.version != TILE_PLACEMENT_VERSION_ID {
-      return Err(String::from("header version for placement is wrong"));
+  }
+
+  // Whether every pixel of the tile is part of the canvas at the timestamp.
+  pub fn is_open(&self, timestamp: u64) -> bool {
+    timestamp >= self.active_from && (self.areas.is_empty() || self.areas.iter().any(|a| {
+      a.from <= timestamp && a.x0 == 0 && a.y0 == 0 && a.x1 == self.size && a.y1 == self.size
+    }))
+  }
+
+  // Clears the pixels of an image of the tile that are not part of the
+  // canvas yet at the timestamp, leaving them transparent.
+  pub fn mask(&self, image: &mut [u8], timestamp: u64) {
+    if self.is_open(timestamp) {
+      return
     }
+    let size = self.size as usize;
+    let mut open = vec![false; size * size];
+    for a in self.areas.iter().filter(|a| a.from <= timestamp) {
+      for y in a.y0 as usize..a.y1 as usize {
+        open[y * size + a.x0 as usize..y * size + a.x1 as usize].fill(true);
+      }
+    }
+    for (pixel, open) in image.iter_mut().zip(open) {
+      if !open {
+        *pixel = 0;
+      }
+    }
+  }
+
+  fn pixel_index(&self) -> Option<GroupedIndex<'_>> {
+    self.mmap_pixel_index.as_ref().map(|mmap| GroupedIndex::new(
+      mmap.data(),
+      self.size as usize * self.size as usize,
+      self.count as usize
+    ))
+  }
 
-    let mmap_frames = unsafe { MmapOptions::new().map(&frame_file).unwrap() };
-    let header_frames: TileKeyframeHeader = unsafe { ptr::read(mmap_frames.as_ptr() as *const _) };
-    info!("loading frame {:?} with header: {:?}", &placement_filename, header_frames);
-    if header_frames.version != TILE_KEYFRAME_VERSION_ID {
-      return Err(String::from("header version for frame is wrong"));
+  fn user_index(&self) -> Option<GroupedIndex<'_>> {
+    self.mmap_user_index.as_ref().map(|mmap| GroupedIndex::new(
+      mmap.data(),
+      self.max_uid as usize + 1,
+      self.count as usize
+    ))
+  }
+
+  pub fn placements(&self) -> Placements<'_> {
+    match &self.placement_data {
+      // checked by view_placements on load
+      Some(PlacementData::Mapped(file)) => Placements::Slice(unsafe {
+        slice::from_raw_parts(file.data().as_ptr() as *const _, self.count as usize)
+      }),
+      Some(PlacementData::Decoded(placements)) => Placements::Slice(placements),
+      Some(PlacementData::Compact(file, layout)) => Placements::compact(
+        CompactLog::new(file.data(), *layout, self.count as usize)
+      ),
+      None => Placements::Slice(&[])
     }
-    
-    if header_placements.start_x != header_frames.start_x ||
-      header_placements.start_y != header_frames.start_y {
-      return Err(String::from("header hismatch between placements and frames"));
+  }
+
+  // Bytes of the nth stored frame, decompressing it if needed.
+  fn frame_section(&self, n: usize) -> Option<Cow<'_, [u8]>> {
+    let data = self.mmap_frames.as_ref()?.data();
+    let count = self.frame_count as usize;
+    let (len, offset) = self.frame_layout.section(n, count, self.size as usize * self.size as usize);
+    if !self.frames_compressed {
+      return Some(Cow::Borrowed(&data[offset..offset + len]));
+    }
+    // offsets were checked on load
+    let table = (self.frame_layout.sections(count) + 1) * 8;
+    let start = table + get_u64(data, n * 8) as usize;
+    let end = table + get_u64(data, n * 8 + 8) as usize;
+    match zstd::bulk::decompress(&data[start..end], len) {
+      Ok(bytes) if bytes.len() == len => Some(Cow::Owned(bytes)),
+      _ => {
+        error!("unable to decompress frame {} of tile at {},{}", n, self.start_x, self.start_y);
+        None
+      }
     }
-    
-    Ok(Tile{
-      start: header_placements.start,
-      count: header_placements.count,
-      uid_count: header_placements.uid_count,
-      start_x: header_placements.start_x,
-      start_y: header_placements.start_y,
-      size: header_placements.size,
-      frame_count: header_frames.count,
-      frame_interval: header_frames.interval,
-      mmap_placements: Some(mmap_placements),
-      mmap_frames: Some(mmap_frames),
-    })
   }
 
-  pub fn placements<'a>(&'a self) -> &'a [Placement] {
-    match &self.mmap_placements {
-      Some(mmap) => {
-        unsafe {
-          slice::from_raw_parts(
-            mmap.as_ptr()
-              .offset(mem::size_of::<TilePlacementHeader>() as isize) as *const _,
-            self.count as usize
-          )
-        }
-      },
-      None => &[]
-    }
-  }
-
-  fn frame(&self, id: u32) -> Option<(usize, FrameData)> {
-    match &self.mmap_frames {
-      Some(mmap) => {
-        let idx = cmp::min(self.frame_count - 1, id/self.frame_interval);
-        let size = self.size as usize * self.size as usize;
-        Some((idx as usize * self.frame_interval as usize, unsafe {
-          slice::from_raw_parts(
-            mmap.as_ptr()
-              .offset(
-                mem::size_of::<TileKeyframeHeader>() as isize + idx as isize * (size * 4) as isize
-              ) as *const _,
-            size as usize
-          )
-        }.to_vec()))
+  // Index of the closest keyframe at or before placement id. Frames in a
+  // table are found by the placement's timestamp, but never past the
+  // placement in case the log is out of order or frames share a timestamp.
+  fn frame_before(&self, id: usize) -> Result<Option<usize>> {
+    if self.mmap_frames.is_none() || self.frame_count == 0 {
+      return Ok(None);
+    }
+    Ok(match &self.frame_times {
+      Some(times) => {
+        let ts = self.placements().get(id)?.ts;
+        cmp::min(
+          times.partition_point(|t| t.0 <= ts),
+          times.partition_point(|t| t.1 as usize <= id)
+        ).checked_sub(1)
       },
-      None => None
+      None => Some(cmp::min(self.frame_count as usize - 1, id / self.frame_interval as usize)),
+    })
+  }
+
+  // Index of the first placement not included in keyframe n.
+  fn frame_start(&self, n: usize) -> usize {
+    match &self.frame_times {
+      Some(times) => times[n].1 as usize,
+      None => n * self.frame_interval as usize,
     }
   }
 
-  pub fn get_image_at_timestamp(&self, timestamp: u64) -> Option<FrameData> {
-    let now = Instant::now();
+  fn frame(&self, n: usize) -> Option<FrameData> {
+    let bytes = self.frame_section(n)?;
+    Some(match self.frame_layout {
+      FrameLayout::Packed => bytes.chunks_exact(4).map(|v| v[0]).collect(),
+      FrameLayout::Planes { .. } => bytes.into_owned(),
+    })
+  }
+
+  // The uid plane of keyframe n, if the frames have one. Packed frames read
+  // pixels nobody placed as uid 0.
+  fn uid_frame(&self, n: usize) -> Option<UidFrameData> {
+    let (bytes, shift) = match self.frame_layout {
+      FrameLayout::Packed => (self.frame_section(n)?, 8),
+      FrameLayout::Planes { uids: true } => (self.frame_section(self.frame_count as usize + n)?, 0),
+      FrameLayout::Planes { uids: false } => return None,
+    };
+    Some((0..bytes.len() / 4).map(|i| get_u32(&bytes, i * 4) >> shift).collect())
+  }
 
+  // Index of the first placement at or after the timestamp, which may be past
+  // the end of the log. Returns None if the timestamp precedes the tile.
+  fn index_at_timestamp(&self, timestamp: u64) -> Result<Option<usize>> {
     let placements = self.placements();
-    if (timestamp as i64 - self.start as i64) < 0 {
-      return None;
-    }
-    
+    let last = match placements.last()? {
+      Some(p) if timestamp >= self.start => p,
+      _ => return Ok(None),
+    };
+
     let ts = match u32::try_from(timestamp - self.start){
       Ok(ts) => ts,
-      Err(_) => placements[placements.len() - 1].ts // default to last pixel
+      Err(_) => last.ts // default to last pixel
     };
 
-    let idx = placements.partition_point(|p| ts > p.ts);
+    let idx = placements.index_at_ts(ts)?;
     debug!("index for timestamp is {}/{}", idx, placements.len());
-    if idx >= placements.len() {
-      return None;
-    }
-    
+    Ok(Some(idx))
+  }
+
+  // Logs a placement log read that failed, as with frames that can't be read.
+  fn log_corrupt<T>(&self, result: Result<T>) -> Option<T> {
+    result.map_err(|e| error!("unable to read placements of tile at {},{}: {}", self.start_x, self.start_y, e)).ok()
+  }
+
+  // Renders the tile from the closest keyframe up to and including the
+  // placement at idx.
+  fn render_index(&self, idx: usize) -> Result<FrameData> {
+    let now = Instant::now();
+
     let mut start = 0;
-    let mut output = match self.frame(idx as u32) {
+    let mut output = match self.frame_before(idx)?.and_then(|n| Some((self.frame_start(n), self.frame(n)?))) {
       Some((s, x)) => {
         start = s;
         x
       },
       None => vec![1; self.size as usize * self.size as usize]
     };
-    self.apply(&mut output, &placements[start..=idx]);
+    self.apply(&mut output, self.placements().range(start..idx + 1))?;
 
     debug!("Tile took {:?} to render, replayed {} placements", now.elapsed(), idx - start);
+    Ok(output)
+  }
+
+  // Like render_index, but the uid of the last user to place each pixel.
+  // Replays from the start of the log when the keyframes have no uid plane,
+  // so is only used when has_user_remainders allows it.
+  fn render_uids_index(&self, idx: usize) -> Result<UidFrameData> {
+    let now = Instant::now();
+
+    let mut start = 0;
+    let mut output = match self.frame_before(idx)?.and_then(|n| Some((self.frame_start(n), self.uid_frame(n)?))) {
+      Some((s, x)) => {
+        start = s;
+        x
+      },
+      None => vec![NO_UID; self.size as usize * self.size as usize]
+    };
+    self.apply_uids(&mut output, self.placements().range(start..idx + 1))?;
+
+    debug!("Tile uids took {:?} to render, replayed {} placements", now.elapsed(), idx - start);
+    Ok(output)
+  }
 
-    return Some(output);
+  // Index of the last placement shown at the timestamp, which is the end of
+  // the log for timestamps past it. Returns None if the timestamp precedes
+  // the tile or nothing was placed on it.
+  fn last_index_at(&self, timestamp: u64) -> Result<Option<usize>> {
+    Ok(self.index_at_timestamp(timestamp)?.map(|idx| cmp::min(idx, self.placements().len() - 1)))
+  }
+
+  pub fn get_image_at_timestamp(&self, timestamp: u64) -> Option<FrameData> {
+    Some(self.replay(timestamp)?.image().into_owned())
+  }
+
+  // Starts an incremental replay of the tile at the timestamp.
+  pub fn replay(&self, timestamp: u64) -> Option<Replay<'_>> {
+    if timestamp < self.start {
+      return None;
+    }
+    // nothing is placed before the tile opens or on a tile nobody placed on,
+    // so replay from a blank tile
+    if timestamp < self.active_from || self.placements().is_empty() {
+      return Some(Replay {
+        tile: self,
+        image: vec![1; self.size as usize * self.size as usize],
+        position: 0,
+        timestamp,
+      });
+    }
+    let idx = self.log_corrupt(self.last_index_at(timestamp))??;
+    Some(Replay {
+      tile: self,
+      image: self.log_corrupt(self.render_index(idx))?,
+      position: idx + 1,
+      timestamp,
+    })
   }
 
   pub fn get_diff_for_timestamps(&self, timestamp1: u64, timestamp2: u64) -> Option<FrameData> {
     let img1 = self.get_image_at_timestamp(timestamp1)?;
     let img2 = self.get_image_at_timestamp(timestamp2)?;
 
-    return Some(img1.iter().zip(img2.iter())
-      .map(|(a, b)| if &a == &b { 0 } else { *b })
-      .collect());
+    Some(img1.iter().zip(img2.iter())
+      .map(|(a, b)| if a == b { 0 } else { *b })
+      .collect())
+  }
+
+  // Every placement by the user in order, or None if the log can't be read.
+  // Uses the user index when loaded rather than scanning every placement.
+  pub fn get_user_placements(&self, user_id: u32) -> Option<Vec<Placement>> {
+    let placements = self.placements();
+    self.log_corrupt(match self.user_index() {
+      Some(index) => index.get(user_id as usize).iter()
+        .map(|i| placements.get(i as usize))
+        .collect(),
+      None => placements.iter().filter(|p| p.as_ref().map_or(true, |p| p.uid == user_id)).collect()
+    })
   }
 
   pub fn get_image_for_user(&self, user_id: u32) -> Option<FrameData> {
-    if user_id >= self.uid_count {
+    if user_id > self.max_uid {
       return None;
     }
-    let mut img = vec![0u32; self.size as usize * self.size as usize];
-    for p in self.placements().iter().filter(|p| p.uid == user_id) {
-      img[p.x as usize + p.y as usize * self.size as usize] = (p.uid << 8) + (p.color + 1) as u32;
+    let mut img = vec![0; self.size as usize * self.size as usize];
+    for p in self.get_user_placements(user_id)? {
+      img[p.x as usize + p.y as usize * self.size as usize] = p.color + 1;
+    }
+    Some(img)
+  }
+
+  // Whether user remainder images can be rendered without replaying the
+  // whole log, which needs keyframes with uids or both indexes loaded.
+  pub fn has_user_remainders(&self) -> bool {
+    (self.user_index().is_some() && self.pixel_index().is_some()) ||
+      (self.mmap_frames.is_some() && self.frame_layout != FrameLayout::Planes { uids: false })
+  }
+
+  // The pixels last placed by the user at the timestamp. With both the user
+  // and pixel indexes loaded, only the pixels the user touched are looked at.
+  pub fn get_image_for_user_at_timestamp(&self, user_id: u32, timestamp: u64) -> Option<FrameData> {
+    let placements = self.placements();
+    if timestamp >= self.start && placements.is_empty() {
+      return Some(vec![0; self.size as usize * self.size as usize]);
+    }
+    let idx = self.log_corrupt(self.last_index_at(timestamp))??;
+    let (user_index, pixel_index) = match (self.user_index(), self.pixel_index()) {
+      (Some(u), Some(p)) => (u, p),
+      _ => {
+        let uids = self.log_corrupt(self.render_uids_index(idx))?;
+        return Some(self.log_corrupt(self.render_index(idx))?.iter().zip(uids)
+          .map(|(v, uid)| if uid == user_id { *v } else { 0 })
+          .collect());
+      }
+    };
+
+    let mut img = vec![0; self.size as usize * self.size as usize];
+    for i in user_index.get(user_id as usize).iter().take_while(|i| *i as usize <= idx) {
+      let p = self.log_corrupt(placements.get(i as usize))?;
+      let pixel = p.x as usize + p.y as usize * self.size as usize;
+      let history = pixel_index.get(pixel);
+      let last = history.get(history.partition_point(|j| j as usize <= idx) - 1);
+      let last = self.log_corrupt(placements.get(last as usize))?;
+      if last.uid == user_id {
+        img[pixel] = last.color + 1;
+      }
+    }
+    Some(img)
+  }
+
+  // Every placement touching the pixel at x, y relative to the tile, or None
+  // if the log can't be read. Uses the pixel index when loaded rather than
+  // scanning every placement.
+  pub fn get_pixel_history(&self, x: u16, y: u16) -> Option<Vec<Placement>> {
+    let placements = self.placements();
+    self.log_corrupt(match self.pixel_index() {
+      Some(index) => index
+        .get(x as usize + y as usize * self.size as usize).iter()
+        .map(|i| placements.get(i as usize))
+        .collect(),
+      None => placements.iter().filter(|p| p.as_ref().map_or(true, |p| p.x == x && p.y == y)).collect()
+    })
+  }
+
+  // Checks the contents of the tile rather than just its headers, replaying
+  // the log against the keyframes and any loaded indexes. colors is the
+  // number of colours in the dataset palette.
+  pub fn verify(&self, colors: usize) -> Vec<Error> {
+    let mut problems = Vec::new();
+    let mut report = |msg: String| problems.push(Error::Corrupt(format!(
+      "tile at {},{}: {}", self.start_x, self.start_y, msg
+    )));
+    let placements = self.placements();
+
+    let mut checks: [(&str, usize, Option<usize>); 5] = [
+      ("out of order", 0, None),
+      ("outside the tile", 0, None),
+      ("with a uid over the header max uid", 0, None),
+      ("with a colour outside the palette", 0, None),
+      ("with an isblk other than 0 or 1", 0, None),
+    ];
+    let mut prev_ts = 0;
+    for (i, p) in placements.iter().enumerate() {
+      let p = match p {
+        Ok(p) => p,
+        Err(e) => {
+          report(format!("placement {} can't be read: {}", i, e));
+          return problems;
+        }
+      };
+      let failed = [
+        p.ts < prev_ts,
+        p.x >= self.size || p.y >= self.size,
+        p.uid > self.max_uid,
+        p.color as usize >= colors,
+        p.isblk > 1,
+      ];
+      for (check, failed) in checks.iter_mut().zip(failed) {
+        if failed {
+          check.1 += 1;
+          check.2.get_or_insert(i);
+        }
+      }
+      prev_ts = p.ts;
+    }
+    for (name, count, first) in checks {
+      if let Some(first) = first {
+        report(format!("{} placements {}, first at {}", count, name, first));
+      }
+    }
+    // the rest of the checks index by position so can't be trusted past here
+    if checks[1].1 > 0 {
+      return problems;
+    }
+
+    if self.mmap_frames.is_some() {
+      if let Err(e) = self.verify_frames(&placements, &mut report) {
+        report(format!("placements can't be read: {}", e));
+      }
+    }
+
+    let size = self.size as usize;
+    if let Some(index) = self.pixel_index() {
+      // every placement was read above, so none are dropped by map_while
+      let keys: Vec<usize> = placements.iter().map_while(Result::ok)
+        .map(|p| p.x as usize + p.y as usize * size).collect();
+      if let Some(msg) = check_index(&index, size * size, &keys) {
+        report(format!("pixel index {}", msg));
+      }
+    }
+    if let Some(index) = self.user_index() {
+      let keys: Vec<usize> = placements.iter().map_while(Result::ok).map(|p| p.uid as usize).collect();
+      if let Some(msg) = check_index(&index, self.max_uid as usize + 1, &keys) {
+        report(format!("user index {}", msg));
+      }
     }
-    return Some(img);
+    problems
   }
 
+  // Replays the log against each keyframe, reporting frames that don't match.
+  fn verify_frames<F: FnMut(String)>(&self, placements: &Placements, report: &mut F) -> Result<()> {
+    let mut img = vec![1; self.size as usize * self.size as usize];
+    let mut uids = vec![NO_UID; self.size as usize * self.size as usize];
+    let check_uids = self.frame_layout == FrameLayout::Planes { uids: true };
+    let mut prev = 0;
+    for id in 0..self.frame_count as usize {
+      let start = self.frame_start(id);
+      if start > placements.len() {
+        report(format!("frame {} starts past the end of the log", id));
+        break;
+      }
+      if let Some(times) = &self.frame_times {
+        // between the placements either side of it
+        let ts = times[id].0;
+        if (start > 0 && placements.get(start - 1)?.ts > ts) ||
+          (start < placements.len() && placements.get(start)?.ts < ts) {
+          report(format!("frame {} timestamp does not match the log", id));
+        }
+      }
+      self.apply(&mut img, placements.range(prev..start))?;
+      if check_uids {
+        self.apply_uids(&mut uids, placements.range(prev..start))?;
+      }
+      prev = start;
+      match self.frame(id) {
+        Some(frame) if frame != img => report(format!("frame {} does not match the log", id)),
+        Some(_) => (),
+        None => report(format!("frame {} can't be read", id)),
+      }
+      if check_uids {
+        match self.uid_frame(id) {
+          Some(frame) if frame != uids => report(format!("uid frame {} does not match the log", id)),
+          Some(_) => (),
+          None => report(format!("uid frame {} can't be read", id)),
+        }
+      }
+    }
+    Ok(())
+  }
 
-  pub fn apply(&self, img: &mut FrameData, placements: &[Placement]) {
-    for p in placements.iter() {  
-      img[p.x as usize + p.y as usize * self.size as usize] = (p.uid << 8) + (p.color + 1) as u32;
+  pub fn apply<I: IntoIterator<Item = Result<Placement>>>(&self, img: &mut FrameData, placements: I) -> Result<()> {
+    for p in placements {
+      let p = p?;
+      img[p.x as usize + p.y as usize * self.size as usize] = p.color + 1;
     }
+    Ok(())
+  }
+
+  pub fn apply_uids<I: IntoIterator<Item = Result<Placement>>>(&self, uids: &mut [u32], placements: I) -> Result<()> {
+    for p in placements {
+      let p = p?;
+      uids[p.x as usize + p.y as usize * self.size as usize] = p.uid;
+    }
+    Ok(())
+  }
+}
+
+// Image of a tile that can be moved forward in time by only applying the
+// placements since the last timestamp.
+pub struct Replay<'a> {
+  tile: &'a Tile,
+  image: FrameData,
+  position: usize,
+  timestamp: u64,
+}
+
+impl<'a> Replay<'a> {
+  pub fn tile(&self) -> &'a Tile {
+    self.tile
+  }
+
+  // The image at the last timestamp, with the parts of the tile that were
+  // not open yet transparent.
+  pub fn image(&self) -> Cow<'_, FrameData> {
+    if self.tile.is_open(self.timestamp) {
+      return Cow::Borrowed(&self.image);
+    }
+    let mut image = self.image.clone();
+    self.tile.mask(&mut image, self.timestamp);
+    Cow::Owned(image)
+  }
+
+  pub fn advance(&mut self, timestamp: u64) {
+    self.timestamp = timestamp;
+    if timestamp < self.tile.active_from {
+      return
+    }
+    let placements = self.tile.placements();
+    let end = match self.tile.log_corrupt(self.tile.index_at_timestamp(timestamp)) {
+      Some(Some(idx)) => cmp::min(idx + 1, placements.len()),
+      _ => return
+    };
+    if end > self.position {
+      // a failed read leaves the image behind, and is retried on the next advance
+      if self.tile.log_corrupt(self.tile.apply(&mut self.image, placements.range(self.position..end))).is_some() {
+        self.position = end;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use crate::models::record::TILE_PLACEMENT_VERSION_ID;
+
+  // Writes the placements as a placement log starting at timestamp 0 and
+  // loads it, without keyframes or indexes.
+  pub fn load_log(start_x: u16, start_y: u16, size: u16, placements: &[Placement]) -> Tile {
+    static LOGS: AtomicUsize = AtomicUsize::new(0);
+    let header = TilePlacementHeader {
+      version: TILE_PLACEMENT_VERSION_ID,
+      flags: 0,
+      size,
+      start_x,
+      start_y,
+      start: 0,
+      count: placements.len() as u32,
+      max_uid: placements.iter().map(|p| p.uid).max().unwrap_or(0),
+    };
+    let mut data = vec![0u8; TilePlacementHeader::SIZE + placements.len() * Placement::SIZE];
+    header.encode(&mut data);
+    for (p, buf) in placements.iter().zip(data[TilePlacementHeader::SIZE..].chunks_exact_mut(Placement::SIZE)) {
+      p.encode(buf);
+    }
+    let path = std::env::temp_dir().join(format!(
+      "placeviewer-test-{}-{}.bin", std::process::id(), LOGS.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, &data).unwrap();
+    let tile = Tile::load(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+    tile.unwrap()
+  }
+
+  #[test]
+  fn any_isblk_byte_loads() {
+    // the second placement's isblk byte, byte 13, is 2
+    let placement = Placement { ts: 0, uid: 1, x: 3, y: 4, color: 1, isblk: 0 };
+    let tile = load_log(0, 0, 16, &[placement, Placement { ts: 1, isblk: 2, ..placement }]);
+
+    let placements = tile.placements();
+    assert!(!placements.get(0).unwrap().is_blk());
+    assert!(placements.get(1).unwrap().is_blk());
+    let problems = tile.verify(2);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].to_string().contains("isblk"), "{}", problems[0]);
   }
 }
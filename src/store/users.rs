@@ -0,0 +1,107 @@
+use rayon::prelude::*;
+use std::io::{BufWriter, Write};
+use std::str;
+
+use crate::error::{Error, Result};
+use crate::models::record::{
+  get_u32, get_u64, write_record, UserDictionaryHeader, USER_DICTIONARY_VERSION_ID
+};
+use super::tile::{check_length, map_with_header, MappedFile};
+
+// The original ids of users that were given integer ids while parsing, see
+// UserDictionaryHeader.
+#[derive(Debug)]
+pub struct UserDictionary {
+  file: MappedFile,
+  count: usize,
+}
+
+impl UserDictionary {
+  pub fn load(filename: &str) -> Result<UserDictionary> {
+    let (header, file) = map_with_header::<UserDictionaryHeader>(filename)?;
+    let count = header.count as usize;
+    check_length(filename, &file, (count + 1) * 8 + count * 4)?;
+    let users = UserDictionary { file, count };
+    if !users.is_valid() {
+      return Err(Error::Corrupt(format!("user dictionary {} has bad offsets or order", filename)));
+    }
+    Ok(users)
+  }
+
+  pub fn len(&self) -> usize {
+    self.count
+  }
+
+  fn offset(&self, uid: usize) -> usize {
+    get_u64(self.file.data(), uid * 8) as usize
+  }
+
+  fn names(&self) -> &[u8] {
+    &self.file.data()[(self.count + 1) * 8 + self.count * 4..]
+  }
+
+  fn sorted(&self, n: usize) -> u32 {
+    get_u32(self.file.data(), (self.count + 1) * 8 + n * 4)
+  }
+
+  fn name_bytes(&self, uid: usize) -> &[u8] {
+    &self.names()[self.offset(uid)..self.offset(uid + 1)]
+  }
+
+  // Whether the offsets are in order and inside the file, and the sorted
+  // uids are in range and in order, which lookups rely on.
+  fn is_valid(&self) -> bool {
+    self.offset(0) == 0 &&
+      (0..self.count).all(|uid| self.offset(uid) <= self.offset(uid + 1)) &&
+      self.offset(self.count) <= self.names().len() &&
+      (0..self.count).all(|n| (self.sorted(n) as usize) < self.count) &&
+      (1..self.count).all(|n| {
+        self.name_bytes(self.sorted(n - 1) as usize) < self.name_bytes(self.sorted(n) as usize)
+      })
+  }
+
+  // Original id of the user given uid.
+  pub fn name(&self, uid: u32) -> Option<&str> {
+    if uid as usize >= self.count {
+      return None
+    }
+    str::from_utf8(self.name_bytes(uid as usize)).ok()
+  }
+
+  // The uid given to the user with the original id.
+  pub fn uid(&self, name: &str) -> Option<u32> {
+    let (mut lo, mut hi) = (0, self.count);
+    while lo < hi {
+      let mid = lo + (hi - lo) / 2;
+      let uid = self.sorted(mid);
+      match self.name_bytes(uid as usize).cmp(name.as_bytes()) {
+        std::cmp::Ordering::Less => lo = mid + 1,
+        std::cmp::Ordering::Greater => hi = mid,
+        std::cmp::Ordering::Equal => return Some(uid),
+      }
+    }
+    None
+  }
+}
+
+// Writes a dictionary of the original ids of users, indexed by uid.
+pub fn write_user_dictionary<W: Write>(names: &[Vec<u8>], w: &mut BufWriter<W>) -> Result<()> {
+  let header = UserDictionaryHeader { version: USER_DICTIONARY_VERSION_ID, flags: 0, count: names.len() as u32 };
+  write_record(&header, w)?;
+  let mut offset = 0u64;
+  w.write_all(&offset.to_le_bytes())?;
+  for name in names.iter() {
+    offset += name.len() as u64;
+    w.write_all(&offset.to_le_bytes())?;
+  }
+  let mut sorted: Vec<u32> = (0..names.len() as u32).collect();
+  sorted.par_sort_unstable_by(|a, b| names[*a as usize].cmp(&names[*b as usize]));
+  for uid in sorted.iter() {
+    w.write_all(&uid.to_le_bytes())?;
+  }
+  for name in names.iter() {
+    w.write_all(name)?;
+  }
+  w.flush()?;
+  Ok(())
+}